@@ -0,0 +1,63 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use multiplayer_game::codec;
+use multiplayer_game::game_state::{GameEvent, GameState, Vec2, BULLET_VEL, PLAYER_RADIUS_SIZE, TICK_DT};
+use multiplayer_game::snapshot::StateDelta;
+
+fn game_state(players: usize, shots_per_player: usize) -> GameState {
+    let mut game = GameState::new();
+    for i in 0..players {
+        let name = format!("player{}", i);
+        game.add_player(&name, Vec2 { x: 37.0 * i as f32, y: 11.5 * i as f32 });
+        game.react_to_event(GameEvent::UpdateVelocity { x: 20.0, y: -10.0, name: name.clone() });
+        game.react_to_event(GameEvent::UpdateAngle { angle: 13.0 * i as f32, name: name.clone() });
+    }
+    game.step(TICK_DT);
+    // Weapon cooldowns only let one shot a tick through, so the bullets are
+    // put in directly, a few units apart along each player's aim.
+    let shots: Vec<(Vec2, f32, String)> = game.players.values()
+        .map(|player| (player.position.clone(), player.angle, player.name.clone()))
+        .collect();
+    for (position, angle, name) in shots {
+        for shot in 0..shots_per_player {
+            let offset = Vec2::with_angle(angle, PLAYER_RADIUS_SIZE + 1.0 + 3.0 * shot as f32);
+            game.add_bullet(position.sum(&offset), Vec2::with_angle(angle, BULLET_VEL), &name);
+        }
+    }
+    assert_eq!(game.bullets.len(), players * shots_per_player);
+    game
+}
+
+fn codecs(c: &mut Criterion) {
+    let cases = [
+        ("small", game_state(4, 2)),
+        ("large", game_state(32, 10)),
+    ];
+    let codecs = [codec::by_name(codec::JSON).unwrap(), codec::by_name(codec::BINARY).unwrap()];
+
+    let mut group = c.benchmark_group("codec_round_trip");
+    for (name, state) in &cases {
+        let mut next = state.clone();
+        next.step(TICK_DT);
+        let events = [
+            ("sync", GameEvent::GameStateSync(state.clone())),
+            ("delta", GameEvent::GameStateDelta(StateDelta::diff(state, &next))),
+        ];
+        for (kind, event) in &events {
+            for codec in &codecs {
+                let bytes = codec.encode_event(event).unwrap();
+                println!("{}/{}/{}: {} bytes", name, kind, codec.name(), bytes.len());
+                group.throughput(Throughput::Bytes(bytes.len() as u64));
+                group.bench_with_input(BenchmarkId::new(format!("{}/{}", name, kind), codec.name()), event, |b, event| {
+                    b.iter(|| {
+                        let bytes = codec.encode_event(black_box(event)).unwrap();
+                        codec.decode_event(&bytes).unwrap()
+                    })
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, codecs);
+criterion_main!(benches);
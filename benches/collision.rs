@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use multiplayer_game::game_state::{BulletState, GameState, PlayerState, Vec2, PLAYER_RADIUS_SIZE, TICK_DT};
+use multiplayer_game::map::Map;
+use multiplayer_game::spatial::{sweep_circle, SpatialGrid, GRID_CELL_SIZE};
+
+const WIDTH: f32 = 4000.0;
+const HEIGHT: f32 = 3000.0;
+
+/// Cheap deterministic positions spread over the arena.
+fn scatter(seed: u32, count: usize) -> Vec<Vec2> {
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 8) as f32 / (1 << 24) as f32
+    };
+    (0..count).map(|_| Vec2 { x: next() * WIDTH, y: next() * HEIGHT }).collect()
+}
+
+fn game_state(players: usize, bullets: usize) -> GameState {
+    let mut game = GameState::new();
+    game.map = Arc::new(Map { width: WIDTH, height: HEIGHT, ..Map::default() });
+    scatter(1, players).into_iter().enumerate()
+        .for_each(|(i, position)| game.add_player(&format!("player{}", i), position));
+    scatter(2, bullets).into_iter().enumerate()
+        .for_each(|(i, position)| {
+            game.add_bullet(position, Vec2 { x: 0.0, y: 0.0 }, &format!("player{}", i % players));
+        });
+    game
+}
+
+/// Where the bullets were a tick ago, moving fast enough to skip a player.
+fn previous_positions(game: &GameState) -> Vec<Vec2> {
+    game.bullets.values().map(|state| Vec2 { x: state.position.x - 30.0, y: state.position.y - 15.0 }).collect()
+}
+
+/// Every bullet swept against every player.
+fn brute_force<'a>(bullet: &BulletState, from: &Vec2, players: &[&'a PlayerState]) -> Option<(f32, &'a PlayerState)> {
+    players.iter()
+        .filter(|player| player.can_be_hit(0, &bullet.shooter))
+        .filter_map(|player| sweep_circle(from, &bullet.position, &player.position, PLAYER_RADIUS_SIZE).map(|t| (t, *player)))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+fn collisions(c: &mut Criterion) {
+    let cases = [(100, 1000), (300, 3000), (500, 5000)];
+
+    let mut group = c.benchmark_group("bullet_player_hits");
+    for (players, bullets) in cases {
+        let game = game_state(players, bullets);
+        let from = previous_positions(&game);
+        let players: Vec<&PlayerState> = game.players.values().collect();
+        let label = format!("{}x{}", players.len(), bullets);
+        let hits = game.bullets.values().zip(&from).filter(|(bullet, from)| brute_force(bullet, from, &players).is_some()).count();
+        println!("{}: {} hits", label, hits);
+        group.bench_with_input(BenchmarkId::new("brute_force", &label), &game, |b, game| {
+            b.iter(|| game.bullets.values().zip(&from)
+                .filter_map(|(bullet, from)| brute_force(bullet, from, black_box(&players)))
+                .count())
+        });
+        group.bench_with_input(BenchmarkId::new("grid", &label), &game, |b, game| {
+            b.iter(|| {
+                let grid = SpatialGrid::build(GRID_CELL_SIZE, black_box(&players).iter().map(|state| &state.position));
+                game.bullets.values().zip(&from)
+                    .filter_map(|(bullet, from)| bullet.first_hit(from, game.tick, &players, &grid))
+                    .count()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("step");
+    for (players, bullets) in cases {
+        let game = game_state(players, bullets);
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", players, bullets)), &game, |b, game| {
+            b.iter_batched(|| game.clone(), |mut game| game.step(TICK_DT), criterion::BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, collisions);
+criterion_main!(benches);
@@ -1,138 +1,213 @@
-use std::{collections::HashMap, thread, time};
+use std::{thread, time};
 use std::sync::mpsc::{self, Sender, Receiver};
 use macroquad::ui::{hash, root_ui, widgets};
-use serde_json::{from_str, to_string};
-use multiplayer_game::handler::EnterLobby;
-use multiplayer_game::ws::{self, Commands};
-use multiplayer_game::{handler::{CreateLobbyRequest, LobbyResponse}, game_state::{self, GameEvent, GameState, PlayerState}, time_util};
+use multiplayer_game::handler::{self, EnterLobby};
+use multiplayer_game::ws::{ClientMessage, Commands};
+use multiplayer_game::snapshot::DeltaDecoder;
+use multiplayer_game::codec;
+use multiplayer_game::map::{Map, Obstacle};
+use multiplayer_game::weapon::Weapon;
+use multiplayer_game::pickup::{self, EffectKind, Pickup, PickupKind};
+use multiplayer_game::error::ErrorResponse;
+use multiplayer_game::protocol::{Hello, Welcome};
+use multiplayer_game::prediction::Prediction;
+use multiplayer_game::interpolation::{self, InterpolationBuffer};
+use multiplayer_game::{handler::{CreateLobbyRequest, LobbyInfo, LobbyResponse}, game_state::{self, GameEvent, GameState, MatchPhase, PlayerState}, time_util::{self, FixedTimestep}};
 use macroquad::prelude::*;
-use game_state::Vec2;
 use reqwest::blocking;
-use tungstenite::{connect, Message};
 use url::Url;
+use clap::Parser;
 use tokio::runtime::Runtime;
-use tokio_tungstenite::{connect_async, tungstenite::protocol};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::{self, CloseFrame}};
 use futures::{StreamExt, SinkExt};
 
 
 
-const URL: &str = "http://localhost:8000";
+/// Number keys pick the weapon in the matching inventory slot.
+const WEAPON_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5,
+    KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9
+];
+
+#[derive(Parser, Debug)]
+#[command(about = "Runs the game client")]
+struct ClientArgs {
+    /// Base URL of the game server's HTTP API.
+    #[arg(long, env = "GAME_SERVER", default_value = "http://localhost:8000")]
+    server: String,
+    /// Codec to prefer for the game connection. JSON if not given, which
+    /// is the easiest to debug.
+    #[arg(long, env = "GAME_CODEC", value_parser = [codec::BINARY, codec::JSON])]
+    codec: Option<String>
+}
 
 pub enum SetupMessage {
-    CreateLobby {lobby_name: String, player_name: String},
-    EnterLobby {lobby_name: String, player_name: String},
-    LobbyEntered {url: String, game_state: Option<GameState>}
+    CreateLobby {lobby_name: String, player_name: String, password: Option<String>},
+    EnterLobby {lobby_name: String, player_name: String, password: Option<String>},
+    LobbyEntered {url: String, token: String, game_state: Option<Box<GameState>>},
+    ListLobbies,
+    Lobbies(Vec<LobbyInfo>),
+    Failed(String)
 }
 
 
 #[macroquad::main("MultiplayerGame")]
 async fn main() {
+    let args = ClientArgs::parse();
+    let server = args.server.trim_end_matches('/').to_string();
+    let codec_name = args.codec.clone().unwrap_or_else(|| codec::default_codec().name().to_string());
 
     let (sender_setup, receiver_setup): (Sender<SetupMessage>, Receiver<SetupMessage>) = mpsc::channel();
     let (sender_lobby_enter, receiver_lobby_enter): (Sender<SetupMessage>, Receiver<SetupMessage>) = mpsc::channel();
 
 
     thread::spawn(move || {
-        let msg = receiver_setup.recv().unwrap();
-        let res = match msg {
-            SetupMessage::CreateLobby { lobby_name, player_name } => {
-                let client = blocking::Client::new();
-                let req = client
-                    .post(format!("{URL}/create_lobby"))
-                    .json(&CreateLobbyRequest {name: lobby_name.clone(), player_name: player_name.clone()});
-                println!("{:?}", req);
-                let res = req.send()
-                    .unwrap();
-                println!("{:?}", res);
-                let res = res.json()
-                    .unwrap();
-                
-                //let res: LobbyResponse = from_str(&res).unwrap();
-                Some(res)
-            }, 
-            SetupMessage::EnterLobby { lobby_name, player_name } => {
-                let client = blocking::Client::new();
-                let req = client
-                    .post(format!("{URL}/register"))
+        while let Ok(msg) = receiver_setup.recv() {
+            let client = blocking::Client::new();
+            if let SetupMessage::ListLobbies = msg {
+                let reply = match client.get(format!("{server}/lobbies")).send().and_then(|res| res.json()) {
+                    Ok(lobbies) => SetupMessage::Lobbies(lobbies),
+                    Err(e) => SetupMessage::Failed(format!("could not list lobbies: {e}"))
+                };
+                sender_lobby_enter.send(reply).unwrap();
+                continue;
+            }
+            let req = match msg {
+                SetupMessage::CreateLobby { lobby_name, player_name, password } => client
+                    .post(format!("{server}/create_lobby"))
+                    .json(&CreateLobbyRequest {
+                        name: lobby_name.clone(),
+                        player_name: player_name.clone(),
+                        reconnect_grace_secs: None,
+                        max_players: None,
+                        password
+                    }),
+                SetupMessage::EnterLobby { lobby_name, player_name, password } => client
+                    .post(format!("{server}/register"))
                     .json(&EnterLobby {
                         name: lobby_name.clone(), 
-                        player_name: player_name.clone()
-                    });
-                println!("{:?}", req);
-                let res = req
-                        .send()
-                        .unwrap();
-                println!("{:?}", res);
-                let res: LobbyResponse = res
-                        .json()
-                        .unwrap();
-
-                Some(res)
-            },
-            _ => {None}
-
-        };
-        if let Some(res) = res {
-            sender_lobby_enter.send(SetupMessage::LobbyEntered { 
-                url: res.url,
-                game_state: res.game_state }).unwrap();
+                        player_name: player_name.clone(),
+                        password
+                    }),
+                _ => continue
+            };
+            let reply = match req.send() {
+                Ok(res) if res.status().is_success() => match res.json::<LobbyResponse>() {
+                    Ok(res) => SetupMessage::LobbyEntered {
+                        url: res.url,
+                        token: res.token,
+                        game_state: res.game_state.map(Box::new) },
+                    Err(e) => SetupMessage::Failed(format!("bad reply from server: {e}"))
+                },
+                Ok(res) => {
+                    let status = res.status();
+                    let reason = res.json::<ErrorResponse>().map(|e| e.message).unwrap_or_default();
+                    SetupMessage::Failed(format!("server refused ({status}): {reason}"))
+                },
+                Err(e) => SetupMessage::Failed(format!("could not reach server: {e}"))
+            };
+            sender_lobby_enter.send(reply).unwrap();
         }
     });
 
     let mut events_receiver: Option<_> = None;
     let mut action_sender: Option<_> = None;
     let mut in_lobby_menu = true;
-    let mut game_state = GameState {
-        players: HashMap::new(),
-        bullets: Vec::with_capacity(50),
-        last_time: time_util::get_current_time(),
-        actions: Vec::with_capacity(10)
-    };
+    let mut game_state = GameState::new();
+    let mut tick_dt = game_state::TICK_DT;
+    let mut timestep = FixedTimestep::new(tick_dt, time_util::get_current_time());
+    let mut prediction = Prediction::new("");
+    let mut interpolation = InterpolationBuffer::new(interpolation::DEFAULT_DELAY);
+    let mut decoder = DeltaDecoder::new();
     
     let mut vertical: f32 = 0.0;
     let mut horizontal: f32 = 0.0;
     let mut lobby_name = String::new();
     let mut player_name = String::new();
+    let mut password = String::new();
+    let mut status = String::new();
+    // The url and token of the last lobby joined, kept to reconnect with.
+    let mut session: Option<(String, String)> = None;
+    let mut lobby_list: Vec<LobbyInfo> = Vec::new();
+    sender_setup.send(SetupMessage::ListLobbies).unwrap();
     loop {
         clear_background(WHITE);
         if in_lobby_menu {
+            let mut reconnect = false;
             //show ui
-            widgets::Window::new(hash!(), vec2(470., 50.), vec2(300., 300.))
+            widgets::Window::new(hash!(), vec2(470., 50.), vec2(300., 400.))
                 .label("lobby menu")
-                .ui(&mut *root_ui(), |ui| {
+                .ui(&mut root_ui(), |ui| {
+                    if ui.button(None, "REFRESH LOBBIES") {
+                        sender_setup.send(SetupMessage::ListLobbies).unwrap();
+                    }
+                    for lobby in &lobby_list {
+                        let marker = if lobby.name == lobby_name { ">" } else { " " };
+                        let lock = if lobby.password_protected { " [locked]" } else { "" };
+                        let label = format!("{} {} ({}/{}) {:?}{}", marker, lobby.name, lobby.player_count, lobby.max_players, lobby.game_mode, lock);
+                        if ui.button(None, label.as_str()) {
+                            lobby_name = lobby.name.clone();
+                        }
+                    }
+                    ui.separator();
                     ui.input_text(hash!(), "<- lobby name", &mut lobby_name);
                     ui.input_text(hash!(), "<- player name", &mut player_name);
+                    ui.input_text(hash!(), "<- password (optional)", &mut password);
+                    let password = Some(password.clone()).filter(|password| !password.is_empty());
+                    if !status.is_empty() {
+                        ui.label(None, &status);
+                    }
 
                     if ui.button(None, "CREATE LOBBY") {
                         sender_setup.send(SetupMessage::CreateLobby { 
                             lobby_name: lobby_name.clone(), 
-                            player_name: player_name.clone() }).unwrap();
+                            player_name: player_name.clone(),
+                            password: password.clone() }).unwrap();
                     }
                     if ui.button(None, "ENTER LOBBY") {
                         sender_setup.send(SetupMessage::EnterLobby  { 
                             lobby_name: lobby_name.clone(), 
-                            player_name: player_name.clone() }).unwrap();
+                            player_name: player_name.clone(),
+                            password }).unwrap();
+                    }
+                    if session.is_some() && ui.button(None, "RECONNECT") {
+                        reconnect = true;
                     }
                 });
-            if let Ok(msg) = receiver_lobby_enter.try_recv() {
-                match msg {
-                    SetupMessage::LobbyEntered { url, game_state: game } => {
-                        let (receiver, sender) = spawn_comm_threads_async(url);
-                        in_lobby_menu = false;
-                        events_receiver = Some(receiver);
-                        action_sender = Some(sender);
-                        if let Some(game) = game {
-                            game_state = game;
-                        }
-                    },
-                    _ => {}
-                }
+            // Reconnecting reattaches us to our player, and the full sync the
+            // server sends first is picked up by the reconciliation below.
+            let mut joined = reconnect;
+            match receiver_lobby_enter.try_recv() {
+                Ok(SetupMessage::LobbyEntered { url, token, game_state: game }) => {
+                    session = Some((url, token));
+                    if let Some(game) = game {
+                        game_state = *game;
+                    }
+                    joined = true;
+                },
+                Ok(SetupMessage::Lobbies(lobbies)) => lobby_list = lobbies,
+                Ok(SetupMessage::Failed(reason)) => status = reason,
+                _ => {}
+            }
+            if let (true, Some((url, token))) = (joined, &session) {
+                let (receiver, sender) = spawn_comm_threads_async(url, token, &codec_name);
+                in_lobby_menu = false;
+                events_receiver = Some(receiver);
+                action_sender = Some(sender);
+                timestep = FixedTimestep::new(tick_dt, time_util::get_current_time());
+                prediction = Prediction::new(&player_name);
+                interpolation.clear();
+                decoder = DeltaDecoder::new();
+                status.clear();
             }
         } else {
             let mut actions = Vec::with_capacity(10);
             if is_key_pressed(KeyCode::Space) {
-                actions.push(GameEvent::Shooting(player_name.clone()));
+                actions.push(Commands::Shoot);
             };
+            if let Some(slot) = WEAPON_KEYS.iter().position(|key| is_key_pressed(*key)) {
+                actions.push(Commands::SwitchWeapon(slot));
+            }
 
             let mut state_change = false;
             if is_key_pressed(KeyCode::A) {
@@ -149,52 +224,129 @@ async fn main() {
                 vertical = 1.0;
                 state_change = true;
             }
-            if is_key_released(KeyCode::A) {
-                horizontal = 0.0;
-                state_change = true;
-            } else if is_key_released(KeyCode::D) {
+            if is_key_released(KeyCode::A) || is_key_released(KeyCode::D) {
                 horizontal = 0.0;
                 state_change = true;
             }
-            if is_key_released(KeyCode::W) {
-                vertical = 0.0;
-                state_change = true;
-            } else if is_key_released(KeyCode::S) {
+            if is_key_released(KeyCode::W) || is_key_released(KeyCode::S) {
                 vertical = 0.0;
                 state_change = true;
             }
             
             if state_change {
                 if horizontal.abs() > 0.0 || vertical.abs() > 0.0 {
-                    let vel = vec2(horizontal, vertical).normalize() * 30.0;
-                    actions.push(GameEvent::UpdateVelocity { x: vel.x, y: vel.y, name: player_name.clone() });
+                    let vel = vec2(horizontal, vertical).normalize() * game_state::PLAYER_MAX_VEL;
+                    actions.push(Commands::UpdateVelocity { x: vel.x, y: vel.y });
                 } else {
-                    actions.push(GameEvent::UpdateVelocity { x: 0.0, y: 0.0, name: player_name.clone() });
+                    actions.push(Commands::UpdateVelocity { x: 0.0, y: 0.0 });
                 }
             }
 
-            while actions.len() > 0 {
-                let action = actions.pop().unwrap();
-                println!("trying to send: {:?}", action);
-                //action_sender.as_ref().unwrap().send(action).unwrap();
-                action_sender.as_ref().unwrap().send(action).map_err(|e| println!("{e}")).unwrap();
+            // Sends only fail once the connection's writer has stopped, and
+            // the reader normally reports why right after.
+            let mut connection_lost = false;
+            for action in actions.drain(..) {
+                let input = prediction.apply(&mut game_state, action);
+                connection_lost |= action_sender.as_ref().is_none_or(|sender| sender.send(ClientMessage::Input(input)).is_err());
             }
 
-            game_state.update(time_util::get_current_time());
-            game_state.players.iter().for_each(|(_, player)| {
-                let x = player.position.x;
-                let y = player.position.y;
-                draw_circle(x, y, game_state::PLAYER_RADIUS_SIZE, RED);
-            });
-            game_state.bullets.iter().for_each(|bullet| {
+            while let Ok(incoming) = events_receiver.as_mut().unwrap().try_recv() {
+                let event = match incoming {
+                    Incoming::Event(event) => *event,
+                    Incoming::Welcome(welcome) => {
+                        tick_dt = 1.0 / welcome.tick_rate as f32;
+                        timestep = FixedTimestep::new(tick_dt, time_util::get_current_time());
+                        prediction.set_tick_dt(tick_dt);
+                        continue;
+                    },
+                    Incoming::Closed(reason) => {
+                        println!("{reason}");
+                        status = reason;
+                        // Stops the writer too, closing the socket if it is
+                        // still open.
+                        action_sender = None;
+                        in_lobby_menu = true;
+                        sender_setup.send(SetupMessage::ListLobbies).unwrap();
+                        break;
+                    }
+                };
+                match event {
+                    GameEvent::GameStateSync(_) | GameEvent::GameStateDelta(_) => {
+                        if let Some(authoritative) = decoder.decode(&event) {
+                            connection_lost |= action_sender.as_ref().is_none_or(|sender| sender.send(ClientMessage::Ack(authoritative.tick)).is_err());
+                            interpolation.push(time_util::get_current_time(), authoritative.clone());
+                            prediction.reconcile(&mut game_state, authoritative);
+                        }
+                    },
+                    GameEvent::LobbyClosed | GameEvent::ServerShutdown { .. } => {
+                        status = match event {
+                            GameEvent::ServerShutdown { reason } => format!("server shut down: {reason}"),
+                            _ => "lobby closed".to_string()
+                        };
+                        session = None;
+                        in_lobby_menu = true;
+                        sender_setup.send(SetupMessage::ListLobbies).unwrap();
+                        break;
+                    },
+                    GameEvent::Error(message) => {
+                        println!("server error: {message}");
+                        status = message;
+                    },
+                    event if prediction.is_predicted(&event) => {},
+                    event => game_state.react_to_event(event)
+                }
+            }
+            if connection_lost && !in_lobby_menu {
+                status = "connection lost".to_string();
+                in_lobby_menu = true;
+                sender_setup.send(SetupMessage::ListLobbies).unwrap();
+            }
+            for _ in 0..timestep.ticks(time_util::get_current_time()) {
+                game_state.step(tick_dt);
+            }
+            draw_map(&game_state.map);
+            // The local player is drawn where prediction puts it, everyone
+            // else a little in the past from the interpolation buffer.
+            let remote = interpolation.sample(time_util::get_current_time());
+            let view = remote.as_ref().unwrap_or(&game_state);
+            view.pickups.values().for_each(draw_pickup);
+            view.players.iter()
+                .filter(|(name, _)| **name != player_name)
+                .chain(game_state.players.get_key_value(&player_name))
+                .for_each(|(_, player)| {
+                    let x = player.position.x;
+                    let y = player.position.y;
+                    if player.alive {
+                        draw_circle(x, y, game_state::PLAYER_RADIUS_SIZE, RED);
+                        if player.is_protected(view.tick) {
+                            draw_circle_lines(x, y, game_state::PLAYER_RADIUS_SIZE + 3.0, 2.0, SKYBLUE);
+                        }
+                        if player.has_effect(EffectKind::Shield) {
+                            draw_circle_lines(x, y, game_state::PLAYER_RADIUS_SIZE + 6.0, 2.0, GOLD);
+                        }
+                        draw_health_bar(x, y, player.health);
+                    } else {
+                        draw_circle(x, y, game_state::PLAYER_RADIUS_SIZE, GRAY);
+                    }
+                });
+            view.bullets.values().for_each(|bullet| {
                 let x = bullet.position.x;
                 let y = bullet.position.y;
                 draw_circle(x, y, game_state::BULLET_RADIUS_SIZE, BLACK);
 
             });
-            if let Ok(event) = events_receiver.as_mut().unwrap().try_recv() {
-                game_state.react_to_event(event);
-            } 
+            if let Some(me) = game_state.players.get(&player_name).filter(|me| !me.alive) {
+                let seconds = me.respawn_tick.saturating_sub(game_state.tick) as f32 * tick_dt;
+                draw_text(&format!("respawning in {:.1}s", seconds), 20.0, 40.0, 32.0, DARKGRAY);
+            }
+            draw_match_phase(&game_state.match_state.phase, game_state.tick, tick_dt);
+            if let Some(me) = game_state.players.get(&player_name) {
+                draw_weapons(me, &game_state.weapons);
+                draw_effects(me);
+            }
+            if is_key_down(KeyCode::Tab) || game_state.match_state.phase == MatchPhase::MatchOver {
+                draw_scoreboard(&handler::scoreboard(game_state.scores.iter()));
+            }
         }
         thread::sleep(time::Duration::from_millis(25));
         next_frame().await;
@@ -202,76 +354,215 @@ async fn main() {
 }
 
 
-fn spawn_comm_threads(url: String) -> (Receiver<GameEvent>, Sender<ws::Commands>) {
-    let (events_sender, events_receiver): (Sender<GameEvent>, Receiver<GameEvent>) = mpsc::channel();
-    let (action_sender, action_receiver): (Sender<ws::Commands>, Receiver<ws::Commands>) = mpsc::channel();
+fn draw_map(map: &Map) {
+    draw_rectangle_lines(0.0, 0.0, map.width, map.height, 4.0, DARKGRAY);
+    map.obstacles.iter().for_each(|obstacle| match *obstacle {
+        Obstacle::Rect { x, y, width, height } => draw_rectangle(x, y, width, height, DARKGRAY),
+        Obstacle::Circle { x, y, radius } => draw_circle(x, y, radius, DARKGRAY)
+    });
+}
+
+/// Draws a bar above the player at `(x, y)`, filled in proportion to its
+/// remaining health.
+fn draw_health_bar(x: f32, y: f32, health: i32) {
+    let width = game_state::PLAYER_RADIUS_SIZE * 2.0;
+    let left = x - game_state::PLAYER_RADIUS_SIZE;
+    let top = y - game_state::PLAYER_RADIUS_SIZE - 6.0;
+    let filled = width * health.clamp(0, game_state::PLAYER_MAX_HEALTH) as f32 / game_state::PLAYER_MAX_HEALTH as f32;
+    draw_rectangle(left, top, width, 3.0, DARKGRAY);
+    draw_rectangle(left, top, filled, 3.0, GREEN);
+}
+
+/// A line at the top of the screen with the phase and its remaining time.
+fn draw_match_phase(phase: &MatchPhase, tick: u64, tick_dt: f32) {
+    let text = match phase {
+        MatchPhase::WaitingForPlayers => "waiting for players".to_string(),
+        MatchPhase::Countdown { .. } => "starting in".to_string(),
+        MatchPhase::InProgress { round, .. } => format!("round {round}"),
+        MatchPhase::RoundOver { winner: Some(winner), .. } => format!("{winner} wins the round"),
+        MatchPhase::RoundOver { winner: None, .. } => "round drawn".to_string(),
+        MatchPhase::MatchOver => "match over".to_string()
+    };
+    let text = match phase.ends_at() {
+        Some(ends_at) => format!("{text} {:.0}s", ends_at.saturating_sub(tick) as f32 * tick_dt),
+        None => text
+    };
+    let size = measure_text(&text, None, 24, 1.0);
+    draw_text(&text, (screen_width() - size.width) / 2.0, 24.0, 24.0, DARKGRAY);
+}
 
-    let url = Url::parse(&url).unwrap();
-    println!("{:?}", url);
-    let (mut socket, response) = connect(url).map_err(|e| eprintln!("{e}")).expect("cant connect");
-    thread::spawn(move || {
-        loop {
-            let msg = action_receiver.recv().unwrap();
-            socket.write_message(Message::Text(to_string(&msg).unwrap())).unwrap();
+/// The player's weapons in the bottom left corner, with the one in hand
+/// highlighted, and how much ammo they have left.
+fn draw_weapons(player: &PlayerState, weapons: &[Weapon]) {
+    let bottom = screen_height() - 20.0;
+    player.weapons.iter().enumerate().for_each(|(slot, state)| {
+        let Some(weapon) = weapons.get(state.weapon) else {
+            return;
+        };
+        let ammo = if state.reloading > 0.0 {
+            format!("reloading {:.1}s", state.reloading)
+        } else {
+            format!("{}/{}", state.ammo, weapon.magazine)
+        };
+        let color = if slot == player.current_weapon { BLACK } else { GRAY };
+        let top = bottom - 22.0 * (player.weapons.len() - 1 - slot) as f32;
+        draw_text(&format!("{} {:<8} {}", slot + 1, weapon.name, ammo), 20.0, top, 22.0, color);
+    });
+}
 
-            let msg = socket.read_message().unwrap().to_text().unwrap().to_string();
-            println!("{msg}");
-            events_sender.send(from_str(&msg).unwrap()).unwrap();
-        }
+/// A pickup as a circle colored by kind, labelled with what it is.
+fn draw_pickup(pickup: &Pickup) {
+    let color = match pickup.kind {
+        PickupKind::Health { .. } => GREEN,
+        PickupKind::Ammo => BROWN,
+        PickupKind::SpeedBoost { .. } => BLUE,
+        PickupKind::Shield { .. } => GOLD,
+        PickupKind::WeaponCrate { .. } => PURPLE
+    };
+    let (x, y) = (pickup.position.x, pickup.position.y);
+    draw_circle(x, y, pickup::PICKUP_RADIUS_SIZE, color);
+    let name = pickup.kind.name();
+    let size = measure_text(name, None, 16, 1.0);
+    draw_text(name, x - size.width / 2.0, y + pickup::PICKUP_RADIUS_SIZE + 14.0, 16.0, DARKGRAY);
+}
+
+/// The player's active effects and their remaining time, in the bottom
+/// right corner.
+fn draw_effects(player: &PlayerState) {
+    let bottom = screen_height() - 20.0;
+    player.effects.iter().rev().enumerate().for_each(|(row, effect)| {
+        let text = format!("{} {:.1}s", effect.kind.name(), effect.remaining);
+        let size = measure_text(&text, None, 22, 1.0);
+        draw_text(&text, screen_width() - size.width - 20.0, bottom - 22.0 * row as f32, 22.0, BLACK);
     });
+}
 
-    // thread::spawn(move || {
-    //     loop {
-    //         let msg = action_receiver.recv().unwrap();
-    //         socket.write_message(Message::Text(to_string(&msg).unwrap())).unwrap();
-    //     }
-    // });
+/// The overlay shown while Tab is held, and once the match is over.
+fn draw_scoreboard(entries: &[handler::ScoreboardEntry]) {
+    let (left, top, row) = (150.0, 80.0, 24.0);
+    let width = screen_width() - 2.0 * left;
+    draw_rectangle(left, top, width, row * (entries.len() + 2) as f32, Color::new(0.0, 0.0, 0.0, 0.7));
+    draw_text("player          kills  deaths  damage  accuracy", left + 10.0, top + row, 20.0, WHITE);
+    entries.iter().enumerate().for_each(|(i, entry)| {
+        let line = format!("{:<16}{:>5}{:>8}{:>8}{:>9.0}%",
+            entry.name, entry.kills, entry.deaths, entry.damage_dealt, entry.accuracy * 100.0);
+        draw_text(&line, left + 10.0, top + row * (i + 2) as f32, 20.0, WHITE);
+    });
+}
 
-    (events_receiver, action_sender)
+/// What the connection thread hands to the game loop.
+pub enum Incoming {
+    Welcome(Welcome),
+    Event(Box<GameEvent>),
+    Closed(String)
 }
 
+fn close_reason(frame: Option<CloseFrame>) -> String {
+    match frame {
+        Some(frame) => format!("connection closed ({}): {}", u16::from(frame.code), frame.reason),
+        None => "connection closed".to_string()
+    }
+}
 
-fn spawn_comm_threads_async(url: String) -> (tokio::sync::mpsc::UnboundedReceiver<GameEvent>, tokio::sync::mpsc::UnboundedSender<GameEvent>) {
+fn spawn_comm_threads_async(url: &str, token: &str, codec_name: &str) -> (tokio::sync::mpsc::UnboundedReceiver<Incoming>, tokio::sync::mpsc::UnboundedSender<ClientMessage>) {
     let (events_sender, events_receiver) =  tokio::sync::mpsc::unbounded_channel();
     let (action_sender, mut action_receiver) = tokio::sync::mpsc::unbounded_channel();
 
-    let url = Url::parse(&url).unwrap();
+    let mut url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(e) => {
+            let _ = events_sender.send(Incoming::Closed(format!("bad lobby url {url}: {e}")));
+            return (events_receiver, action_sender);
+        }
+    };
+    url.query_pairs_mut().append_pair("token", token).append_pair("codec", codec_name);
+    let codec_name = codec_name.to_string();
     println!("{:?}", url);
     thread::spawn(move || {
 
         let rt = Runtime::new().unwrap();
         rt.block_on(async move {
-            let (mut socket, response) = connect_async(url).await.map_err(|e| eprintln!("{e}")).expect("cant connect");
+            let socket = match connect_async(url).await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    let _ = events_sender.send(Incoming::Closed(format!("could not connect: {e}")));
+                    return;
+                }
+            };
             let (mut writer, mut reader) = socket.split();
-            let handle = tokio::spawn(async move {
-                loop {
-                    let action: GameEvent = action_receiver.recv().await.unwrap();
-                    writer.send(protocol::Message::Text(to_string(&action).unwrap())).await.unwrap();
 
-                    // if let Ok(action) = action_receiver.try_recv() {
-                    //     writer.send(protocol::Message::Text(to_string(&action).unwrap())).await;
-                    // }
+            let hello = Hello::new(&[&codec_name, codec::JSON]);
+            if let Err(e) = writer.send(protocol::Message::Text(serde_json::to_string(&hello).unwrap())).await {
+                let _ = events_sender.send(Incoming::Closed(format!("connection lost: {e}")));
+                return;
+            }
+            let welcome: Welcome = match reader.next().await {
+                Some(Ok(protocol::Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(welcome) => welcome,
+                    Err(e) => {
+                        let _ = events_sender.send(Incoming::Closed(format!("bad handshake reply: {e}")));
+                        return;
+                    }
+                },
+                Some(Ok(protocol::Message::Close(frame))) => {
+                    let _ = events_sender.send(Incoming::Closed(close_reason(frame)));
+                    return;
+                },
+                other => {
+                    let _ = events_sender.send(Incoming::Closed(format!("bad handshake reply: {:?}", other)));
+                    return;
+                }
+            };
+            let Some(codec) = codec::by_name(&welcome.codec) else {
+                let _ = events_sender.send(Incoming::Closed(format!("server picked unknown codec {}", welcome.codec)));
+                return;
+            };
+            let reader_codec = codec.clone();
+            if events_sender.send(Incoming::Welcome(welcome)).is_err() {
+                return;
+            }
+
+            let handle = tokio::spawn(async move {
+                while let Some(action) = action_receiver.recv().await {
+                    let bytes = match codec.encode_message(&action) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            println!("could not encode {:?}: {}", action, e);
+                            continue;
+                        }
+                    };
+                    let msg = if codec.is_binary() {
+                        protocol::Message::Binary(bytes)
+                    } else {
+                        protocol::Message::Text(String::from_utf8_lossy(&bytes).into_owned())
+                    };
+                    if writer.send(msg).await.is_err() {
+                        break;
+                    }
                 }
-                
             });
             let handle1 = tokio::spawn(async move {
-                while let Some(event) = reader.next().await {
-                    println!("received event: {:?}", event);
-                    events_sender.send(from_str(&event.unwrap().to_text().unwrap()).unwrap()).unwrap();
+                while let Some(msg) = reader.next().await {
+                    let incoming = match msg {
+                        Ok(protocol::Message::Close(frame)) => Incoming::Closed(close_reason(frame)),
+                        Ok(msg) if msg.is_text() || msg.is_binary() => match reader_codec.decode_event(&msg.into_data()) {
+                            Ok(event) => Incoming::Event(Box::new(event)),
+                            Err(e) => Incoming::Closed(format!("bad message from the server: {e}"))
+                        },
+                        Ok(_) => continue,
+                        Err(e) => Incoming::Closed(format!("connection lost: {e}"))
+                    };
+                    let closed = matches!(incoming, Incoming::Closed(_));
+                    if events_sender.send(incoming).is_err() || closed {
+                        break;
+                    }
                 }
             });
 
-            tokio::join!(handle, handle1);
+            let _ = tokio::join!(handle, handle1);
         });
         
     });
 
-    // thread::spawn(move || {
-    //     loop {
-    //         let msg = action_receiver.recv().unwrap();
-    //         socket.write_message(Message::Text(to_string(&msg).unwrap())).unwrap();
-    //     }
-    // });
-
     (events_receiver, action_sender)
-}
\ No newline at end of file
+}
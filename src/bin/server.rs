@@ -1,6 +1,16 @@
 
 
+use clap::Parser;
+use multiplayer_game::config::{ServerArgs, ServerConfig};
+
 #[tokio::main]
 async fn main() {
-    multiplayer_game::server().await;
+    let config = match ServerConfig::load(ServerArgs::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    multiplayer_game::server(config).await;
 }
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::sync::Arc;
+use serde::{de::DeserializeOwned, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use crate::{game_state::{GameEvent, Vec2}, ws::ClientMessage};
+
+pub const JSON: &str = "json";
+pub const BINARY: &str = "binary";
+
+#[derive(Debug)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec error: {}", self.0)
+    }
+}
+
+/// How `GameEvent`s and `ClientMessage`s are put on the wire. A codec is
+/// picked per connection; `is_binary` tells whether its frames go out as
+/// binary or text WebSocket messages.
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn is_binary(&self) -> bool;
+    fn encode_event(&self, event: &GameEvent) -> Result<Vec<u8>, CodecError>;
+    fn decode_event(&self, bytes: &[u8]) -> Result<GameEvent, CodecError>;
+    fn encode_message(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError>;
+    fn decode_message(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError>;
+}
+
+/// Plain `serde_json`, readable in any WebSocket inspector.
+pub struct JsonCodec;
+
+impl JsonCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        JSON
+    }
+
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    fn encode_event(&self, event: &GameEvent) -> Result<Vec<u8>, CodecError> {
+        Self::encode(event)
+    }
+
+    fn decode_event(&self, bytes: &[u8]) -> Result<GameEvent, CodecError> {
+        Self::decode(bytes)
+    }
+
+    fn encode_message(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        Self::encode(msg)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        Self::decode(bytes)
+    }
+}
+
+/// `postcard` with varint integers. Positions and angles are quantized on
+/// the way out (see `quantize`), so they round-trip only approximately.
+pub struct BinaryCodec;
+
+impl BinaryCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        postcard::to_stdvec(value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        postcard::from_bytes(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+impl Codec for BinaryCodec {
+    fn name(&self) -> &'static str {
+        BINARY
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn encode_event(&self, event: &GameEvent) -> Result<Vec<u8>, CodecError> {
+        Self::encode(event)
+    }
+
+    fn decode_event(&self, bytes: &[u8]) -> Result<GameEvent, CodecError> {
+        Self::decode(bytes)
+    }
+
+    fn encode_message(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        Self::encode(msg)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        Self::decode(bytes)
+    }
+}
+
+pub fn by_name(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        JSON => Some(Arc::new(JsonCodec)),
+        BINARY => Some(Arc::new(BinaryCodec)),
+        _ => None
+    }
+}
+
+pub fn default_codec() -> Arc<dyn Codec> {
+    Arc::new(JsonCodec)
+}
+
+/// Fixed-point encodings used by non human-readable formats. JSON keeps
+/// full `f32`s so that it stays easy to debug.
+pub mod quantize {
+    use super::*;
+
+    /// Positions and velocities are sent in hundredths of a unit.
+    pub const POSITION_SCALE: f32 = 100.0;
+    /// Angles are sent as a `u16` covering a full turn.
+    pub const ANGLE_STEPS: f32 = 65536.0;
+
+    pub fn position(value: f32) -> i32 {
+        (value * POSITION_SCALE).round() as i32
+    }
+
+    pub fn unposition(value: i32) -> f32 {
+        value as f32 / POSITION_SCALE
+    }
+
+    pub fn angle<S: Serializer>(angle: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            angle.serialize(serializer)
+        } else {
+            let steps = (angle.rem_euclid(360.0) / 360.0 * ANGLE_STEPS).round() as u32 % ANGLE_STEPS as u32;
+            (steps as u16).serialize(serializer)
+        }
+    }
+
+    pub fn unangle<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        if deserializer.is_human_readable() {
+            f32::deserialize(deserializer)
+        } else {
+            Ok(u16::deserialize(deserializer)? as f32 / ANGLE_STEPS * 360.0)
+        }
+    }
+}
+
+impl Serialize for Vec2 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut state = serializer.serialize_struct("Vec2", 2)?;
+            state.serialize_field("x", &self.x)?;
+            state.serialize_field("y", &self.y)?;
+            state.end()
+        } else {
+            (quantize::position(self.x), quantize::position(self.y)).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Vec2 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            x: f32,
+            y: f32
+        }
+
+        if deserializer.is_human_readable() {
+            let Fields { x, y } = Fields::deserialize(deserializer)?;
+            Ok(Vec2 { x, y })
+        } else {
+            let (x, y) = <(i32, i32)>::deserialize(deserializer)?;
+            Ok(Vec2 { x: quantize::unposition(x), y: quantize::unposition(y) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::game_state::{GameState, TICK_DT};
+    use crate::snapshot::StateDelta;
+    use crate::ws::{Commands, Input};
+
+    fn sample_state() -> GameState {
+        let mut game = GameState::new();
+        for i in 0..8 {
+            let name = format!("player{}", i);
+            game.add_player(&name, Vec2 { x: 40.0 * i as f32, y: 25.5 * i as f32 });
+            game.react_to_event(GameEvent::UpdateVelocity { x: 12.5, y: -3.25, name: name.clone() });
+            game.react_to_event(GameEvent::UpdateAngle { angle: 45.0 * i as f32, name: name.clone() });
+            game.react_to_event(GameEvent::Shooting(name));
+        }
+        game.step(TICK_DT);
+        game
+    }
+
+    #[test]
+    fn json_round_trip_is_exact() {
+        let state = sample_state();
+        let bytes = JsonCodec.encode_event(&GameEvent::GameStateSync(state.clone())).unwrap();
+        match JsonCodec.decode_event(&bytes).unwrap() {
+            GameEvent::GameStateSync(decoded) => {
+                assert_eq!(serde_json::to_string(&decoded).unwrap(), serde_json::to_string(&state).unwrap());
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+    }
+
+    #[test]
+    fn binary_round_trip_is_quantized_and_smaller() {
+        let state = sample_state();
+        let event = GameEvent::GameStateSync(state.clone());
+        let binary = BinaryCodec.encode_event(&event).unwrap();
+        let json = JsonCodec.encode_event(&event).unwrap();
+        assert!(binary.len() * 3 < json.len(), "binary {} json {}", binary.len(), json.len());
+
+        let decoded = match BinaryCodec.decode_event(&binary).unwrap() {
+            GameEvent::GameStateSync(decoded) => decoded,
+            other => panic!("unexpected event {:?}", other)
+        };
+        assert_eq!(decoded.tick, state.tick);
+        state.players.iter().for_each(|(name, player)| {
+            let other = &decoded.players[name];
+            assert!((other.position.x - player.position.x).abs() <= 0.5 / quantize::POSITION_SCALE);
+            assert!((other.position.y - player.position.y).abs() <= 0.5 / quantize::POSITION_SCALE);
+            assert!((other.angle - player.angle).abs() < 0.01);
+        });
+        assert_eq!(decoded.bullets.len(), state.bullets.len());
+
+        let mut next = state.clone();
+        next.step(TICK_DT);
+        let delta = GameEvent::GameStateDelta(StateDelta::diff(&state, &next));
+        assert!(matches!(BinaryCodec.decode_event(&BinaryCodec.encode_event(&delta).unwrap()).unwrap(), GameEvent::GameStateDelta(_)));
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let messages = [
+            ClientMessage::Ack(9000),
+            ClientMessage::Input(Input { seq: 3, command: Commands::Shoot }),
+            ClientMessage::Input(Input { seq: 4, command: Commands::UpdateAngle(90.5) }),
+        ];
+        for codec in [by_name(JSON).unwrap(), by_name(BINARY).unwrap()] {
+            for msg in &messages {
+                let bytes = codec.encode_message(msg).unwrap();
+                assert_eq!(&codec.decode_message(&bytes).unwrap(), msg);
+            }
+        }
+        assert!(by_name("xml").is_none());
+        assert_eq!(default_codec().name(), JSON);
+    }
+}
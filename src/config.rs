@@ -0,0 +1,453 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use crate::map::{Map, MapError};
+use crate::weapon::{default_weapons, Weapon};
+use crate::pickup::PickupKind;
+use crate::game_state::{MatchRules, TICK_RATE, DEFAULT_RESPAWN_DELAY_SECS, DEFAULT_SPAWN_PROTECTION_SECS};
+
+pub const DEFAULT_BIND: ([u8; 4], u16) = ([127, 0, 0, 1], 8000);
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 20;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
+pub const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(10);
+/// Longest timeout or delay the config accepts. Far more than any game
+/// needs, and small enough that it fits in ticks at any tick rate.
+pub const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Everything the server can be configured with. It is read from an
+/// optional TOML file, e.g.
+///
+/// ```toml
+/// bind = "0.0.0.0:8000"
+/// public_url = "wss://game.example.com"
+/// tick_rate = 60
+/// map = "maps/pillars.ron"
+///
+/// [lobby]
+/// max_players = 16
+///
+/// [lobby.match]
+/// frag_limit = 20
+/// rounds = 3
+///
+/// [[weapons]]
+/// name = "pistol"
+/// # ... see `Weapon`
+/// ```
+///
+/// and then overridden by environment variables and command line flags (see
+/// `ServerArgs`). Missing fields keep their defaults.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Base of the WebSocket URLs handed to clients, e.g. when running
+    /// behind a proxy. `ws://{bind}` if not given.
+    pub public_url: Option<String>,
+    pub tick_rate: u32,
+    pub broadcast_capacity: usize,
+    /// How long a lobby may stay without players before it is closed.
+    pub idle_timeout_secs: f32,
+    /// `idle_timeout_secs` as checked by `validate`.
+    #[serde(skip)]
+    pub idle_timeout: Duration,
+    /// JSON or RON map file every lobby is played on.
+    pub map: Option<PathBuf>,
+    /// The map read from `map`, or the default empty arena.
+    #[serde(skip)]
+    pub arena: Arc<Map>,
+    pub lobby: LobbyDefaults,
+    /// What every player carries. Replaces the default pistol, shotgun and
+    /// rifle when given.
+    pub weapons: Vec<Weapon>
+}
+
+/// Used for lobbies whose `CreateLobbyRequest` leaves these out.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbyDefaults {
+    pub max_players: usize,
+    pub reconnect_grace_secs: f32,
+    /// `reconnect_grace_secs` as checked by `ServerConfig::validate`.
+    #[serde(skip)]
+    pub reconnect_grace: Duration,
+    /// How long dead players wait before coming back.
+    pub respawn_delay_secs: f32,
+    /// How long respawned players can't be hit.
+    pub spawn_protection_secs: f32,
+    #[serde(rename = "match")]
+    pub match_rules: MatchConfig
+}
+
+/// `MatchRules` in seconds rather than ticks.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    pub min_players: usize,
+    pub countdown_secs: f32,
+    pub frag_limit: Option<u32>,
+    pub time_limit_secs: Option<f32>,
+    pub last_player_standing: bool,
+    pub rounds: u32,
+    pub round_over_secs: f32
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        let rules = MatchRules::default();
+        let secs = |ticks: u64| ticks as f32 / TICK_RATE as f32;
+        MatchConfig {
+            min_players: rules.min_players,
+            countdown_secs: secs(rules.countdown_ticks),
+            frag_limit: rules.frag_limit,
+            time_limit_secs: rules.time_limit_ticks.map(secs),
+            last_player_standing: rules.last_player_standing,
+            rounds: rules.rounds,
+            round_over_secs: secs(rules.round_over_ticks)
+        }
+    }
+}
+
+impl MatchConfig {
+    pub fn rules(&self, tick_rate: u32) -> MatchRules {
+        let ticks = |secs: f32| (secs * tick_rate as f32).ceil() as u64;
+        MatchRules {
+            min_players: self.min_players,
+            countdown_ticks: ticks(self.countdown_secs),
+            frag_limit: self.frag_limit,
+            time_limit_ticks: self.time_limit_secs.map(ticks),
+            last_player_standing: self.last_player_standing,
+            rounds: self.rounds,
+            round_over_ticks: ticks(self.round_over_secs)
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: DEFAULT_BIND.into(),
+            public_url: None,
+            tick_rate: TICK_RATE,
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT.as_secs_f32(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            map: None,
+            arena: Arc::new(Map::default()),
+            lobby: LobbyDefaults::default(),
+            weapons: default_weapons()
+        }
+    }
+}
+
+impl Default for LobbyDefaults {
+    fn default() -> Self {
+        LobbyDefaults {
+            max_players: DEFAULT_MAX_PLAYERS,
+            reconnect_grace_secs: DEFAULT_RECONNECT_GRACE.as_secs_f32(),
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            respawn_delay_secs: DEFAULT_RESPAWN_DELAY_SECS,
+            spawn_protection_secs: DEFAULT_SPAWN_PROTECTION_SECS,
+            match_rules: MatchConfig::default()
+        }
+    }
+}
+
+/// Command line flags of the server binary. Each one can also be set through
+/// the environment variable next to it; flags win over variables, which win
+/// over the config file.
+#[derive(Parser, Debug, Default)]
+#[command(about = "Runs the game server")]
+pub struct ServerArgs {
+    /// TOML file to read the configuration from.
+    #[arg(long, env = "GAME_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on.
+    #[arg(long, env = "GAME_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Base of the WebSocket URLs handed to clients.
+    #[arg(long, env = "GAME_PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// Simulation ticks per second.
+    #[arg(long, env = "GAME_TICK_RATE")]
+    pub tick_rate: Option<u32>,
+    /// Events buffered per lobby before slow connections lag behind.
+    #[arg(long, env = "GAME_BROADCAST_CAPACITY")]
+    pub broadcast_capacity: Option<usize>,
+    /// Seconds a lobby may stay without players before it is closed.
+    #[arg(long, env = "GAME_IDLE_TIMEOUT_SECS")]
+    pub idle_timeout_secs: Option<f32>,
+    /// JSON or RON map file to play on.
+    #[arg(long, env = "GAME_MAP")]
+    pub map: Option<PathBuf>,
+    /// Player limit of lobbies that don't set their own.
+    #[arg(long, env = "GAME_MAX_PLAYERS")]
+    pub max_players: Option<usize>,
+    /// Seconds a disconnected player is kept for a reconnect, unless the lobby sets its own.
+    #[arg(long, env = "GAME_RECONNECT_GRACE_SECS")]
+    pub reconnect_grace_secs: Option<f32>,
+    /// Seconds dead players wait before respawning.
+    #[arg(long, env = "GAME_RESPAWN_DELAY_SECS")]
+    pub respawn_delay_secs: Option<f32>,
+    /// Seconds respawned players can't be hit.
+    #[arg(long, env = "GAME_SPAWN_PROTECTION_SECS")]
+    pub spawn_protection_secs: Option<f32>,
+    /// Kills that win a round; 0 for none.
+    #[arg(long, env = "GAME_FRAG_LIMIT")]
+    pub frag_limit: Option<u32>,
+    /// Seconds a round lasts; 0 for no limit.
+    #[arg(long, env = "GAME_TIME_LIMIT_SECS")]
+    pub time_limit_secs: Option<f32>,
+    /// Whether the last player alive wins a round, with no respawns.
+    #[arg(long, env = "GAME_LAST_PLAYER_STANDING")]
+    pub last_player_standing: Option<bool>,
+    /// Rounds in a match.
+    #[arg(long, env = "GAME_ROUNDS")]
+    pub rounds: Option<u32>
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+    Map(MapError)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "bad config file: {}", e),
+            ConfigError::Invalid(e) => write!(f, "bad config: {}", e),
+            ConfigError::Map(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Parse)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        Self::from_toml(&text)
+    }
+
+    /// Reads the file named in `args`, if any, applies the flags on top and
+    /// reads the map.
+    pub fn load(args: ServerArgs) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default()
+        };
+        config.apply(args);
+        if let Some(path) = &config.map {
+            config.arena = Arc::new(Map::from_file(path).map_err(ConfigError::Map)?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: ServerArgs) {
+        if let Some(bind) = args.bind {
+            self.bind = bind;
+        }
+        if args.public_url.is_some() {
+            self.public_url = args.public_url;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            self.tick_rate = tick_rate;
+        }
+        if let Some(capacity) = args.broadcast_capacity {
+            self.broadcast_capacity = capacity;
+        }
+        if let Some(timeout) = args.idle_timeout_secs {
+            self.idle_timeout_secs = timeout;
+        }
+        if args.map.is_some() {
+            self.map = args.map;
+        }
+        if let Some(max_players) = args.max_players {
+            self.lobby.max_players = max_players;
+        }
+        if let Some(grace) = args.reconnect_grace_secs {
+            self.lobby.reconnect_grace_secs = grace;
+        }
+        if let Some(delay) = args.respawn_delay_secs {
+            self.lobby.respawn_delay_secs = delay;
+        }
+        if let Some(protection) = args.spawn_protection_secs {
+            self.lobby.spawn_protection_secs = protection;
+        }
+        let match_rules = &mut self.lobby.match_rules;
+        if let Some(limit) = args.frag_limit {
+            match_rules.frag_limit = Some(limit).filter(|limit| *limit > 0);
+        }
+        if let Some(limit) = args.time_limit_secs {
+            match_rules.time_limit_secs = Some(limit).filter(|limit| *limit > 0.0);
+        }
+        if let Some(last_player_standing) = args.last_player_standing {
+            match_rules.last_player_standing = last_player_standing;
+        }
+        if let Some(rounds) = args.rounds {
+            match_rules.rounds = rounds;
+        }
+    }
+
+    /// Checks the config and fills in the `Duration`s of the fields given in
+    /// seconds.
+    pub fn validate(&mut self) -> Result<(), ConfigError> {
+        if self.tick_rate == 0 {
+            return Err(ConfigError::Invalid("tick_rate must be at least 1".to_string()));
+        }
+        if self.broadcast_capacity == 0 {
+            return Err(ConfigError::Invalid("broadcast_capacity must be at least 1".to_string()));
+        }
+        if self.lobby.max_players == 0 {
+            return Err(ConfigError::Invalid("lobby.max_players must be at least 1".to_string()));
+        }
+        self.idle_timeout = duration("idle_timeout_secs", self.idle_timeout_secs)?;
+        self.lobby.reconnect_grace = duration("lobby.reconnect_grace_secs", self.lobby.reconnect_grace_secs)?;
+        duration("lobby.respawn_delay_secs", self.lobby.respawn_delay_secs)?;
+        duration("lobby.spawn_protection_secs", self.lobby.spawn_protection_secs)?;
+        let rules = &self.lobby.match_rules;
+        duration("lobby.match.countdown_secs", rules.countdown_secs)?;
+        duration("lobby.match.round_over_secs", rules.round_over_secs)?;
+        rules.time_limit_secs.map(|limit| duration("lobby.match.time_limit_secs", limit)).transpose()?;
+        if rules.min_players == 0 || rules.rounds == 0 {
+            return Err(ConfigError::Invalid("lobby.match.min_players and rounds must be at least 1".to_string()));
+        }
+        if rules.frag_limit.is_none() && rules.time_limit_secs.is_none() && !rules.last_player_standing {
+            return Err(ConfigError::Invalid("lobby.match needs a frag limit, a time limit or last player standing".to_string()));
+        }
+        if self.weapons.is_empty() {
+            return Err(ConfigError::Invalid("there must be at least one weapon".to_string()));
+        }
+        self.weapons.iter().try_for_each(|weapon| weapon.validate().map_err(|e| ConfigError::Invalid(format!("weapons: {}", e))))?;
+        let unknown_crate = self.arena.pickups.iter().find_map(|pickup| match &pickup.kind {
+            PickupKind::WeaponCrate { weapon } if !self.weapons.iter().any(|known| known.name == *weapon) => Some(weapon),
+            _ => None
+        });
+        if let Some(weapon) = unknown_crate {
+            return Err(ConfigError::Invalid(format!("the map has a crate of {}, which isn't one of the weapons", weapon)));
+        }
+        Ok(())
+    }
+
+    pub fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("ws://{}", self.bind)
+        }
+    }
+}
+
+/// `secs` as a `Duration`, if it is a finite, non-negative number of seconds
+/// no longer than `MAX_DURATION`.
+fn duration(name: &str, secs: f32) -> Result<Duration, ConfigError> {
+    Duration::try_from_secs_f32(secs).ok()
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| ConfigError::Invalid(format!("{} must be between 0 and {} seconds", name, MAX_DURATION.as_secs())))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn toml_fields_are_optional() {
+        let config = ServerConfig::from_toml("tick_rate = 60\n[lobby]\nmax_players = 4\n").unwrap();
+        assert_eq!(config.tick_rate, 60);
+        assert_eq!(config.lobby.max_players, 4);
+        assert_eq!(config.lobby.reconnect_grace_secs, DEFAULT_RECONNECT_GRACE.as_secs_f32());
+        assert_eq!(config.bind, SocketAddr::from(DEFAULT_BIND));
+        assert_eq!(config.public_url(), "ws://127.0.0.1:8000");
+
+        assert!(matches!(ServerConfig::from_toml("tickrate = 60"), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("server-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "bind = \"0.0.0.0:9000\"\npublic_url = \"wss://game.example.com/\"\ntick_rate = 20\n").unwrap();
+        let args = ServerArgs::parse_from(["server", "--config", path.to_str().unwrap(), "--tick-rate", "60", "--max-players", "2"]);
+        let config = ServerConfig::load(args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.bind, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(config.public_url(), "wss://game.example.com");
+        assert_eq!(config.tick_rate, 60);
+        assert_eq!(config.lobby.max_players, 2);
+    }
+
+    #[test]
+    fn match_rules_are_read_in_seconds() {
+        let config = ServerConfig::from_toml("[lobby.match]\nframe_limit = 3\n");
+        assert!(matches!(config, Err(ConfigError::Parse(_))));
+        let config = ServerConfig::from_toml("tick_rate = 20\n[lobby.match]\nfrag_limit = 3\ntime_limit_secs = 60\nrounds = 2\n").unwrap();
+        let rules = config.lobby.match_rules.rules(config.tick_rate);
+        assert_eq!(rules.frag_limit, Some(3));
+        assert_eq!(rules.time_limit_ticks, Some(1200));
+        assert_eq!(rules.rounds, 2);
+
+        let args = ServerArgs::parse_from(["server", "--frag-limit", "0", "--time-limit-secs", "0"]);
+        assert!(matches!(ServerConfig::load(args), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn weapons_replace_the_defaults() {
+        let text = "[[weapons]]\nname = \"railgun\"\ncooldown_secs = 1.5\nprojectile_speed = 2000.0\nspread_degrees = 0.0\npellets = 1\n\
+            damage = 100\nmagazine = 1\nreload_secs = 1.0\nprojectile_lifetime_secs = 1.0\n";
+        let mut config = ServerConfig::from_toml(text).unwrap();
+        assert_eq!(config.weapons.len(), 1);
+        assert_eq!(config.weapons[0].damage, 100);
+        assert!(config.validate().is_ok());
+        assert_eq!(ServerConfig::default().weapons, default_weapons());
+
+        let mut broken = ServerConfig { weapons: vec![Weapon { magazine: 0, ..Weapon::pistol() }], ..ServerConfig::default() };
+        assert!(matches!(broken.validate(), Err(ConfigError::Invalid(_))));
+        let mut unarmed = ServerConfig { weapons: Vec::new(), ..ServerConfig::default() };
+        assert!(matches!(unarmed.validate(), Err(ConfigError::Invalid(_))));
+
+        // The pillars map has a shotgun crate.
+        let arena = Arc::new(Map::from_ron(include_str!("../maps/pillars.ron")).unwrap());
+        assert!(ServerConfig { arena: arena.clone(), ..ServerConfig::default() }.validate().is_ok());
+        let mut no_shotgun = ServerConfig { arena, weapons: vec![Weapon::pistol()], ..ServerConfig::default() };
+        assert!(matches!(no_shotgun.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let args = ServerArgs::parse_from(["server", "--tick-rate", "0"]);
+        assert!(matches!(ServerConfig::load(args), Err(ConfigError::Invalid(_))));
+        let missing = ServerArgs { config: Some(PathBuf::from("/does/not/exist.toml")), ..Default::default() };
+        assert!(matches!(ServerConfig::load(missing), Err(ConfigError::Read(_, _))));
+        let missing = ServerArgs { map: Some(PathBuf::from("/does/not/exist.ron")), ..Default::default() };
+        assert!(matches!(ServerConfig::load(missing), Err(ConfigError::Map(MapError::Read(_, _)))));
+
+        // Durations that don't fit are errors rather than panics later on.
+        let args = ServerArgs::parse_from(["server", "--idle-timeout-secs", "1e30"]);
+        assert!(matches!(ServerConfig::load(args), Err(ConfigError::Invalid(_))));
+        let args = ServerArgs::parse_from(["server", "--reconnect-grace-secs=-1"]);
+        assert!(matches!(ServerConfig::load(args), Err(ConfigError::Invalid(_))));
+        // So are ones that would overflow the tick counter.
+        for flag in ["--respawn-delay-secs", "--spawn-protection-secs", "--time-limit-secs"] {
+            let args = ServerArgs::parse_from(["server", flag, "1e18"]);
+            assert!(matches!(ServerConfig::load(args), Err(ConfigError::Invalid(_))), "{flag}");
+        }
+        let countdown = ServerConfig::from_toml("[lobby.match]\ncountdown_secs = 1e18\nfrag_limit = 3\n");
+        assert!(matches!(countdown.unwrap().validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig::load(ServerArgs::parse_from(["server", "--idle-timeout-secs", "2.5"])).unwrap();
+        assert_eq!(config.idle_timeout, Duration::from_millis(2500));
+    }
+}
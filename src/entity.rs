@@ -0,0 +1,251 @@
+use std::fmt;
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Names an entity in an `Entities` container. Slots are reused once their
+/// entity is gone, but with a new `generation`, so an old ID never refers
+/// to whatever took its place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EntityId {
+    pub index: u32,
+    pub generation: u32
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>
+}
+
+/// Slot map storage handing out generational `EntityId`s. Iteration goes by
+/// slot, so it is the same on every copy of the same container.
+///
+/// Serializes as the list of live `(id, value)` pairs. The generations of
+/// empty slots aren't sent, so only the side that allocates IDs (the server)
+/// is guaranteed never to reissue one.
+#[derive(Debug, Clone)]
+pub struct Entities<T> {
+    slots: Vec<Slot<T>>,
+    /// Empty slots, the most recently emptied last.
+    free: Vec<u32>,
+    len: usize
+}
+
+impl<T> Default for Entities<T> {
+    fn default() -> Self {
+        Entities { slots: Vec::new(), free: Vec::new(), len: 0 }
+    }
+}
+
+impl<T> Entities<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Stores `value` under a fresh ID.
+    pub fn insert(&mut self, value: T) -> EntityId {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 0, value: None });
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.value = Some(value);
+        self.len += 1;
+        EntityId { index, generation: slot.generation }
+    }
+
+    /// Stores `value` under an ID handed out elsewhere, e.g. by the server,
+    /// replacing whatever is in its slot.
+    pub fn insert_at(&mut self, id: EntityId, value: T) -> Option<T> {
+        while self.slots.len() <= id.index as usize {
+            self.free.push(self.slots.len() as u32);
+            self.slots.push(Slot { generation: 0, value: None });
+        }
+        let slot = &mut self.slots[id.index as usize];
+        slot.generation = id.generation;
+        let old = slot.value.replace(value);
+        if old.is_none() {
+            self.free.retain(|&index| index != id.index);
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Takes the entity out, if `id` still refers to it.
+    pub fn remove(&mut self, id: EntityId) -> Option<T> {
+        let slot = self.slots.get_mut(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        let ids: Vec<EntityId> = self.ids().collect();
+        ids.into_iter().for_each(|id| { self.remove(id); });
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&T> {
+        self.slots.get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut T> {
+        self.slots.get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| (EntityId { index: index as u32, generation: slot.generation }, value))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (EntityId, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let generation = slot.generation;
+            slot.value.as_mut().map(|value| (EntityId { index: index as u32, generation }, value))
+        })
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.iter().map(|(id, _)| id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.iter_mut().map(|(_, value)| value)
+    }
+}
+
+impl<T: Serialize> Serialize for Entities<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Binary formats need the length up front, which `iter` can't tell.
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        self.iter().try_for_each(|entry| seq.serialize_element(&entry))?;
+        seq.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Entities<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries: Vec<(EntityId, T)> = Vec::deserialize(deserializer)?;
+        let mut entities = Entities::new();
+        entries.into_iter().for_each(|(id, value)| { entities.insert_at(id, value); });
+        Ok(entities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use proptest::prelude::*;
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Spawn(u32),
+        /// Despawns the n-th ID handed out so far, which may be gone already.
+        Despawn(usize),
+        Clear
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => any::<u32>().prop_map(Op::Spawn),
+            4 => any::<usize>().prop_map(Op::Despawn),
+            1 => Just(Op::Clear)
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn spawns_and_despawns_stay_consistent(ops in proptest::collection::vec(op(), 0..200)) {
+            let mut entities = Entities::new();
+            let mut live: HashMap<EntityId, u32> = HashMap::new();
+            let mut issued: Vec<EntityId> = Vec::new();
+            for op in ops {
+                match op {
+                    Op::Spawn(value) => {
+                        let id = entities.insert(value);
+                        prop_assert!(!issued.contains(&id), "{} was handed out twice", id);
+                        issued.push(id);
+                        live.insert(id, value);
+                    },
+                    Op::Despawn(n) if !issued.is_empty() => {
+                        let id = issued[n % issued.len()];
+                        prop_assert_eq!(entities.remove(id), live.remove(&id));
+                    },
+                    Op::Despawn(_) => {},
+                    Op::Clear => {
+                        entities.clear();
+                        live.clear();
+                    }
+                }
+                prop_assert_eq!(entities.len(), live.len());
+                for id in &issued {
+                    prop_assert_eq!(entities.get(*id), live.get(id));
+                }
+                let mut ids: Vec<EntityId> = entities.ids().collect();
+                prop_assert!(ids.windows(2).all(|pair| pair[0].index < pair[1].index));
+                ids.sort();
+                let mut expected: Vec<EntityId> = live.keys().copied().collect();
+                expected.sort();
+                prop_assert_eq!(ids, expected);
+            }
+
+            let json = serde_json::to_string(&entities).unwrap();
+            let copy: Entities<u32> = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(copy.iter().collect::<Vec<_>>(), entities.iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn stale_ids_miss_reused_slots() {
+        let mut entities = Entities::new();
+        let first = entities.insert("first");
+        assert_eq!(entities.remove(first), Some("first"));
+        let second = entities.insert("second");
+        assert_eq!(second.index, first.index);
+        assert_ne!(second, first);
+        assert_eq!(entities.get(first), None);
+        assert_eq!(entities.remove(first), None);
+        assert_eq!(entities.get(second), Some(&"second"));
+
+        // Replicas place entities where they are told to.
+        let mut replica = Entities::new();
+        replica.insert_at(EntityId { index: 3, generation: 7 }, "far");
+        assert_eq!(replica.len(), 1);
+        assert_eq!(replica.insert("near"), EntityId { index: 2, generation: 0 });
+    }
+}
@@ -0,0 +1,102 @@
+use std::convert::Infallible;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, reject::Reject, reply, Rejection, Reply};
+
+/// Everything a request to the server can fail with. Handlers return these
+/// as rejections, and `handle_rejection` turns them into a status code and an
+/// `ErrorResponse` body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    LobbyNotFound(String),
+    LobbyExists(String),
+    LobbyFull(String),
+    WrongPassword,
+    NameTaken(String),
+    BadRequest(String),
+    Unauthorized,
+    /// The lobby's game loop is gone, e.g. while a socket was connecting.
+    LobbyClosed(String)
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::LobbyNotFound(_) => StatusCode::NOT_FOUND,
+            Error::LobbyExists(_) | Error::LobbyFull(_) | Error::NameTaken(_) => StatusCode::CONFLICT,
+            Error::WrongPassword => StatusCode::FORBIDDEN,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::LobbyClosed(_) => StatusCode::GONE
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LobbyNotFound(name) => write!(f, "no lobby named {:?}", name),
+            Error::LobbyExists(name) => write!(f, "there already is a lobby named {:?}", name),
+            Error::LobbyFull(name) => write!(f, "lobby {:?} is full", name),
+            Error::WrongPassword => write!(f, "wrong lobby password"),
+            Error::NameTaken(name) => write!(f, "{:?} is already taken", name),
+            Error::BadRequest(e) => write!(f, "bad request: {}", e),
+            Error::Unauthorized => write!(f, "missing or invalid session token"),
+            Error::LobbyClosed(name) => write!(f, "lobby {:?} has closed", name)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Also gives `From<Error> for Rejection`, so handlers can use `?`.
+impl Reject for Error {}
+
+/// Body of every error reply.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorResponse {
+    pub code: u16,
+    pub message: String
+}
+
+pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
+    let (status, message) = if let Some(e) = err.find::<Error>() {
+        (e.status(), e.to_string())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, Error::BadRequest(e.to_string()).to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, Error::BadRequest(e.to_string()).to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())
+    } else {
+        println!("unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal error".to_string())
+    };
+    let body = ErrorResponse { code: status.as_u16(), message };
+    Ok(reply::with_status(reply::json(&body), status))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    async fn status_of(err: Rejection) -> StatusCode {
+        handle_rejection(err).await.unwrap().into_response().status()
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_status_codes() {
+        assert_eq!(status_of(Error::LobbyNotFound("l".to_string()).into()).await, StatusCode::NOT_FOUND);
+        assert_eq!(status_of(Error::LobbyExists("l".to_string()).into()).await, StatusCode::CONFLICT);
+        assert_eq!(status_of(Error::LobbyFull("l".to_string()).into()).await, StatusCode::CONFLICT);
+        assert_eq!(status_of(Error::WrongPassword.into()).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_of(Error::NameTaken("pl".to_string()).into()).await, StatusCode::CONFLICT);
+        assert_eq!(status_of(Error::BadRequest("nope".to_string()).into()).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status_of(Error::Unauthorized.into()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(Error::LobbyClosed("l".to_string()).into()).await, StatusCode::GONE);
+        assert_eq!(status_of(warp::reject::not_found()).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,12 +1,28 @@
-use std::collections::HashMap;
-use crate::time_util;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use serde::{Serialize, Deserialize};
+use crate::snapshot::StateDelta;
+use crate::codec::quantize;
+use crate::map::Map;
+use crate::entity::{EntityId, Entities};
+use crate::weapon::{default_weapons, Weapon, WeaponState};
+use crate::pickup::{EffectKind, Pickup, PickupKind, StatusEffect, PICKUP_RADIUS_SIZE, SHIELD_DAMAGE_FACTOR, SPEED_BOOST_FACTOR};
+use crate::spatial::{sweep_circle, SpatialGrid, GRID_CELL_SIZE};
 
 pub const BULLET_VEL: f32 = 20.0;
+pub const PLAYER_MAX_VEL: f32 = 30.0;
 pub const PLAYER_RADIUS_SIZE: f32 = 10.0;
 pub const BULLET_RADIUS_SIZE: f32 = 3.0;
+pub const BULLET_DAMAGE: i32 = 25;
+pub const PLAYER_MAX_HEALTH: i32 = 100;
+pub const DEFAULT_RESPAWN_DELAY_SECS: f32 = 3.0;
+pub const DEFAULT_SPAWN_PROTECTION_SECS: f32 = 2.0;
+pub const TICK_RATE: u32 = 30;
+pub const TICK_DT: f32 = 1.0 / TICK_RATE as f32;
+pub const SNAPSHOT_INTERVAL_TICKS: u64 = 3;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Serialized by hand in `codec` so that binary codecs can quantize it.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Vec2 {
     pub x: f32,
     pub y: f32
@@ -19,8 +35,31 @@ impl Vec2 {
         Vec2 { x: self.x + other.x, y: self.y + other.y}
     }
 
+    pub fn scale(&self, factor: f32) -> Self {
+        Vec2 { x: self.x * factor, y: self.y * factor }
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.x.powi(2) + self.y.powi(2)).sqrt()
+    }
+
+    pub fn clamp_length(&self, max: f32) -> Self {
+        let len = self.length();
+        if len > max {
+            Vec2 { x: self.x * max / len, y: self.y * max / len }
+        } else {
+            self.clone()
+        }
+    }
+
+    pub fn distance_squared(&self, other: &Self) -> f32 {
+        (self.x - other.x).powi(2) + (self.y - other.y).powi(2)
+    }
+
+    /// A vector of length `len` pointing `angle` degrees from the x axis
+    /// towards the y axis, which on screen is clockwise since y points down.
     pub fn with_angle(angle: f32, len: f32) -> Self {
-        Vec2 { x: len * (angle * Vec2::DEG2RAD).cos(), y: len * (angle * Vec2::DEG2RAD).cos() }
+        Vec2 { x: len * (angle * Vec2::DEG2RAD).cos(), y: len * (angle * Vec2::DEG2RAD).sin() }
     }
 }
 
@@ -30,8 +69,36 @@ pub enum GameEvent {
     Shooting(String),
     UpdateVelocity {x: f32, y: f32, name: String},
     UpdateAngle {angle: f32, name: String},
-    Death(String),
-    GameStateSync(GameState)
+    /// `name` takes out the weapon in inventory slot `slot`.
+    SwitchWeapon { slot: usize, name: String },
+    /// A pickup appeared at its spawn.
+    PickupSpawned { id: EntityId, pickup: Pickup },
+    /// `name` walked over the pickup `id` and got what it gives.
+    PickupCollected { id: EntityId, name: String },
+    /// The bullet `bullet` of `shooter` hit `target`, leaving it `remaining`
+    /// health.
+    PlayerHit { target: String, shooter: String, bullet: EntityId, damage: i32, remaining: i32 },
+    /// The player's health reached zero. It stays in the game as a corpse
+    /// with `alive == false`. `killer` is whoever fired the last bullet.
+    Death { name: String, killer: Option<String> },
+    /// A dead player came back to life at `(x, y)`.
+    Respawn { name: String, x: f32, y: f32 },
+    PlayerLeft(String),
+    /// `name`'s score changed to `score`.
+    ScoreUpdate { name: String, score: Score },
+    /// The match moved on to `phase`.
+    PhaseChanged(MatchPhase),
+    /// Sent once, right after the match is over.
+    MatchResults(MatchResults),
+    /// Sent only to the connection that caused it, e.g. for a rejected
+    /// message. Has no effect on the game.
+    Error(String),
+    /// The lobby was deleted or reaped; the socket closes right after.
+    LobbyClosed,
+    /// The whole server is going down; the socket closes right after.
+    ServerShutdown { reason: String },
+    GameStateSync(GameState),
+    GameStateDelta(StateDelta)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,143 +106,889 @@ pub struct PlayerState {
     pub name: String,
     pub position: Vec2,
     pub velocity: Vec2, 
+    #[serde(serialize_with = "quantize::angle", deserialize_with = "quantize::unangle")]
     pub angle: f32,
     pub health: i32,
-    pub alive: bool
+    pub alive: bool,
+    /// Tick at which a dead player comes back.
+    #[serde(default)]
+    pub respawn_tick: u64,
+    /// The player can't be hit before this tick.
+    #[serde(default)]
+    pub protected_until: u64,
+    #[serde(default)]
+    pub last_input_seq: u32,
+    /// One of each weapon in `GameState::weapons`, in the same order.
+    #[serde(default)]
+    pub weapons: Vec<WeaponState>,
+    /// Slot in `weapons` of the weapon in hand.
+    #[serde(default)]
+    pub current_weapon: usize,
+    /// Timed effects from pickups, which end on death.
+    #[serde(default)]
+    pub effects: Vec<StatusEffect>
 }
 
 impl PlayerState {
-    pub fn update(&mut self, delta_time: f32, bullets: &Vec<BulletState>) -> Option<Action> {
-        self.position.x += self.velocity.x * delta_time;
-        self.position.y += self.velocity.y * delta_time;
-        if bullets.iter().any(|state: &BulletState| {
-            (state.position.x - self.position.x).powi(2) +
-            (state.position.y - self.position.y).powi(2) < 
-            PLAYER_RADIUS_SIZE.powi(2)
-        }) {
-            return  Some(Action::DeletePlayer(self.name.clone()));
+    /// Moves the player, sliding along walls and obstacles, and runs down
+    /// its effects. Corpses don't move.
+    pub fn update(&mut self, delta_time: f32, map: &Map) {
+        if !self.alive {
+            return;
         }
+        let speed = if self.has_effect(EffectKind::SpeedBoost) { SPEED_BOOST_FACTOR } else { 1.0 };
+        self.position.x += self.velocity.x * speed * delta_time;
+        self.position.y += self.velocity.y * speed * delta_time;
+        self.position = map.resolve(&self.position, PLAYER_RADIUS_SIZE);
+        self.effects.retain_mut(|effect| {
+            effect.remaining -= delta_time;
+            effect.remaining > 0.0
+        });
+    }
 
-        None
+    pub fn has_effect(&self, kind: EffectKind) -> bool {
+        self.effects.iter().any(|effect| effect.kind == kind)
+    }
+
+    /// Whether walking over a pickup of `kind` does the player any good.
+    /// Weapon crates only do if the player has that weapon and it isn't
+    /// already full.
+    pub fn wants(&self, kind: &PickupKind, weapons: &[Weapon]) -> bool {
+        self.alive && match kind {
+            PickupKind::Health { .. } => self.health < PLAYER_MAX_HEALTH,
+            PickupKind::WeaponCrate { weapon: name } => self.weapons.iter().any(|state| {
+                weapons.get(state.weapon).is_some_and(|weapon| weapon.name == *name && (state.ammo < weapon.magazine || state.reloading > 0.0))
+            }),
+            _ => true
+        }
+    }
+
+    /// Applies what a pickup of `kind` gives. Taking an effect the player
+    /// already has makes it last for the longer of the two.
+    pub fn collect(&mut self, kind: &PickupKind, weapons: &[Weapon]) {
+        match kind {
+            PickupKind::Health { amount } => self.health = (self.health + amount).min(PLAYER_MAX_HEALTH),
+            PickupKind::Ammo => self.weapons.iter_mut().for_each(|state| if let Some(weapon) = weapons.get(state.weapon) {
+                *state = WeaponState::new(state.weapon, weapon);
+            }),
+            PickupKind::WeaponCrate { weapon: name } => {
+                let found = self.weapons.iter().position(|state| weapons.get(state.weapon).is_some_and(|weapon| weapon.name == *name));
+                if let Some(slot) = found {
+                    let state = &mut self.weapons[slot];
+                    *state = WeaponState::new(state.weapon, &weapons[state.weapon]);
+                    self.current_weapon = slot;
+                }
+            },
+            PickupKind::SpeedBoost { .. } | PickupKind::Shield { .. } => if let Some(effect) = kind.effect() {
+                match self.effects.iter_mut().find(|active| active.kind == effect.kind) {
+                    Some(active) => active.remaining = active.remaining.max(effect.remaining),
+                    None => self.effects.push(effect)
+                }
+            }
+        }
+    }
+
+    /// Whether a bullet of `shooter` can hit the player at `tick`. Corpses
+    /// and protected players aren't hit, and players can't hit themselves.
+    pub fn can_be_hit(&self, tick: u64, shooter: &str) -> bool {
+        self.alive && !self.is_protected(tick) && self.name != shooter
+    }
+
+    /// Takes `damage` off the player's health and returns what is left.
+    pub fn take_damage(&mut self, damage: i32) -> i32 {
+        self.health = (self.health - damage).max(0);
+        self.health
+    }
+
+    /// How much of a bullet's `damage` the player actually takes.
+    pub fn damage_from(&self, damage: i32) -> i32 {
+        if self.has_effect(EffectKind::Shield) {
+            (damage as f32 * SHIELD_DAMAGE_FACTOR).round() as i32
+        } else {
+            damage
+        }
+    }
+
+    pub fn die(&mut self, respawn_tick: u64) {
+        self.health = 0;
+        self.alive = false;
+        self.velocity = Vec2 { x: 0.0, y: 0.0 };
+        self.respawn_tick = respawn_tick;
+        self.effects.clear();
+    }
+
+    pub fn respawn(&mut self, position: Vec2, protected_until: u64, weapons: &[Weapon]) {
+        self.position = position;
+        self.velocity = Vec2 { x: 0.0, y: 0.0 };
+        self.health = PLAYER_MAX_HEALTH;
+        self.alive = true;
+        self.protected_until = protected_until;
+        self.effects.clear();
+        self.arm(weapons);
+    }
+
+    /// Gives the player a loaded copy of every weapon and takes out the
+    /// first one.
+    pub fn arm(&mut self, weapons: &[Weapon]) {
+        self.weapons = weapons.iter().enumerate().map(|(index, weapon)| WeaponState::new(index, weapon)).collect();
+        self.current_weapon = 0;
     }
+
+    pub fn weapon(&self) -> Option<&WeaponState> {
+        self.weapons.get(self.current_weapon)
+    }
+
+    /// Runs the cooldown and reload timers of every weapon the player has.
+    pub fn update_weapons(&mut self, delta_time: f32, weapons: &[Weapon]) {
+        self.weapons.iter_mut().for_each(|state| if let Some(weapon) = weapons.get(state.weapon) {
+            state.update(delta_time, weapon);
+        });
+    }
+
+    pub fn is_protected(&self, tick: u64) -> bool {
+        tick < self.protected_until
+    }
+}
+
+/// How long dead players wait and how long they are protected afterwards,
+/// in ticks so that every peer agrees on them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RespawnRules {
+    pub delay_ticks: u64,
+    pub protection_ticks: u64
+}
+
+impl RespawnRules {
+    pub fn from_secs(delay_secs: f32, protection_secs: f32, tick_rate: u32) -> Self {
+        let ticks = |secs: f32| (secs * tick_rate as f32).ceil() as u64;
+        RespawnRules { delay_ticks: ticks(delay_secs), protection_ticks: ticks(protection_secs) }
+    }
+}
+
+impl Default for RespawnRules {
+    fn default() -> Self {
+        Self::from_secs(DEFAULT_RESPAWN_DELAY_SECS, DEFAULT_SPAWN_PROTECTION_SECS, TICK_RATE)
+    }
+}
+
+/// A player's record over the match. Kept after the player leaves so that
+/// the scoreboard still shows it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    pub kills: u32,
+    pub deaths: u32,
+    pub damage_dealt: i32,
+    pub shots_fired: u32,
+    pub shots_hit: u32
+}
+
+impl Score {
+    /// Share of fired bullets that hit someone, between 0 and 1.
+    pub fn accuracy(&self) -> f32 {
+        if self.shots_fired == 0 {
+            0.0
+        } else {
+            self.shots_hit as f32 / self.shots_fired as f32
+        }
+    }
+}
+
+/// Where a lobby's match stands. Ticks in here are `GameState::tick`s.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum MatchPhase {
+    /// Free play until `MatchRules::min_players` have joined. Nothing done
+    /// here counts towards the match.
+    #[default]
+    WaitingForPlayers,
+    /// The next round starts at `ends_at`.
+    Countdown { ends_at: u64 },
+    /// `ends_at` is set when there is a time limit.
+    InProgress { round: u32, ends_at: Option<u64> },
+    /// `winner` is `None` for a draw.
+    RoundOver { round: u32, winner: Option<String>, ends_at: u64 },
+    MatchOver
+}
+
+impl MatchPhase {
+    pub fn ends_at(&self) -> Option<u64> {
+        match self {
+            MatchPhase::Countdown { ends_at } | MatchPhase::RoundOver { ends_at, .. } => Some(*ends_at),
+            MatchPhase::InProgress { ends_at, .. } => *ends_at,
+            MatchPhase::WaitingForPlayers | MatchPhase::MatchOver => None
+        }
+    }
+
+    /// Whether bullets hurt. They still disappear on a hit when they don't.
+    pub fn allows_damage(&self) -> bool {
+        matches!(self, MatchPhase::WaitingForPlayers | MatchPhase::InProgress { .. })
+    }
+}
+
+/// How a match is played, in ticks like `RespawnRules`. A round ends when
+/// any of the enabled win conditions is met.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchRules {
+    pub min_players: usize,
+    pub countdown_ticks: u64,
+    /// Kills in a round that win it.
+    pub frag_limit: Option<u32>,
+    /// Round length; the player with the most kills in the round wins it.
+    pub time_limit_ticks: Option<u64>,
+    /// Dead players stay dead until the round is over, and the last one
+    /// alive wins it.
+    pub last_player_standing: bool,
+    pub rounds: u32,
+    /// Pause between the end of a round and the next countdown.
+    pub round_over_ticks: u64
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        MatchRules {
+            min_players: 2,
+            countdown_ticks: 5 * TICK_RATE as u64,
+            frag_limit: Some(10),
+            time_limit_ticks: Some(300 * TICK_RATE as u64),
+            last_player_standing: false,
+            rounds: 1,
+            round_over_ticks: 5 * TICK_RATE as u64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MatchState {
+    pub phase: MatchPhase,
+    pub rules: MatchRules,
+    /// Number of the current or last round, from 1.
+    pub round: u32,
+    pub rounds_won: BTreeMap<String, u32>,
+    pub round_kills: BTreeMap<String, u32>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Standing {
+    pub name: String,
+    pub rounds_won: u32,
+    pub score: Score
+}
+
+/// Final standings, most rounds won first, then most kills, then fewest
+/// deaths. `winner` is `None` if the top two are tied on rounds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchResults {
+    pub winner: Option<String>,
+    pub standings: Vec<Standing>
 }
 
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BulletState {
     pub position: Vec2,
     velocity: Vec2,
     lifetime: f32,
-    time: f32,
-    index: usize
+    #[serde(default)]
+    pub damage: i32,
+    /// Name of the player who fired the bullet.
+    #[serde(default)]
+    pub shooter: String,
+    pub(crate) time: f32
 }
 
 impl  BulletState {
-    pub fn update(&mut self, delta_time: f32) -> Option<Action>{
+    pub fn new(position: Vec2, velocity: Vec2, shooter: &str, damage: i32, lifetime: f32) -> Self {
+        BulletState { position, velocity, lifetime, damage, shooter: shooter.to_string(), time: 0.0 }
+    }
+
+    /// Moves the bullet `id`, which goes away when it runs out of time.
+    /// Walls and obstacles are up to the caller, which sweeps the move
+    /// against them and the players together.
+    pub fn update(&mut self, id: EntityId, delta_time: f32) -> Option<Action>{
         self.position.x += self.velocity.x * delta_time;
         self.position.y += self.velocity.y * delta_time;
         self.time += delta_time;
         if self.time >= self.lifetime {
-            return Some(Action::DeleteBullet(self.index));
+            return Some(Action::DeleteBullet(id));
         }
         None
     }
 
+    /// The player the bullet ran into first on its way from `from` to where
+    /// it is now, with the fraction of the way at which it did. `grid` holds
+    /// `players` by index.
+    pub fn first_hit<'a>(&self, from: &Vec2, tick: u64, players: &[&'a PlayerState], grid: &SpatialGrid) -> Option<(f32, &'a PlayerState)> {
+        grid.query_segment(from, &self.position, PLAYER_RADIUS_SIZE)
+            .filter(|&index| players[index].can_be_hit(tick, &self.shooter))
+            .filter_map(|index| sweep_circle(from, &self.position, &players[index].position, PLAYER_RADIUS_SIZE).map(|t| (t, index)))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(t, index)| (t, players[index]))
+    }
+
+    /// Where the bullet will be `delta_time` seconds from now, without
+    /// advancing it.
+    pub fn position_after(&self, delta_time: f32) -> Vec2 {
+        Vec2 { x: self.position.x + self.velocity.x * delta_time, y: self.position.y + self.velocity.y * delta_time }
+    }
+
+}
+
+/// An event produced by a player's input, along with the input's sequence
+/// number.
+#[derive(Debug, Clone)]
+pub struct PlayerInput {
+    pub name: String,
+    pub seq: u32,
+    pub event: GameEvent
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Action {
-    DeletePlayer(String),
-    DeleteBullet(usize)
+    /// `target` overlaps the bullet with id `bullet`. Applied on the next
+    /// step, unless the bullet is gone by then.
+    Hit { target: String, bullet: EntityId },
+    DeleteBullet(EntityId)
 }
 
+/// Players are kept in a `BTreeMap` so that every peer iterates them in the
+/// same order and `step` stays deterministic.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameState {
-    pub players: HashMap<String, PlayerState>,
-    pub bullets: Vec<BulletState>,
-    pub last_time: f64, 
-    pub actions: Vec<Action>
+    pub players: BTreeMap<String, PlayerState>,
+    /// Keyed by generational IDs, which stay the same for as long as the
+    /// bullet flies.
+    pub bullets: Entities<BulletState>,
+    pub tick: u64,
+    pub actions: Vec<Action>,
+    #[serde(default)]
+    pub respawn: RespawnRules,
+    /// Never changes during a game, so it is shared between snapshots.
+    #[serde(default)]
+    pub map: Arc<Map>,
+    #[serde(default)]
+    pub scores: BTreeMap<String, Score>,
+    #[serde(default)]
+    pub match_state: MatchState,
+    /// The weapons every player carries, shared between snapshots like the
+    /// map.
+    #[serde(default = "default_arsenal")]
+    pub weapons: Arc<Vec<Weapon>>,
+    #[serde(default)]
+    pub pickups: Entities<Pickup>,
+    /// Seconds until an emptied spawn in `Map::pickups`, by index, gets its
+    /// pickup back.
+    #[serde(default)]
+    pub pickup_respawns: BTreeMap<usize, f32>
+}
+
+fn default_arsenal() -> Arc<Vec<Weapon>> {
+    Arc::new(default_weapons())
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GameState {
-    pub fn update(&mut self, current_time: f64) -> Vec<GameEvent>{
-        let delta_time = (current_time - self.last_time) as f32;
-        self.last_time = current_time;
-        let mut events = Vec::with_capacity(10);
+    pub fn new() -> Self {
+        GameState {
+            players: BTreeMap::new(),
+            bullets: Entities::new(),
+            tick: 0,
+            actions: Vec::with_capacity(10),
+            respawn: RespawnRules::default(),
+            map: Arc::new(Map::default()),
+            scores: BTreeMap::new(),
+            match_state: MatchState::default(),
+            weapons: default_arsenal(),
+            pickups: Entities::new(),
+            pickup_respawns: BTreeMap::new()
+        }
+    }
 
-        while !self.actions.is_empty() {
-            match self.actions.pop().unwrap() {
-                Action::DeleteBullet(idx) => {
-                    self.bullets.iter_mut().for_each(|bullet| if bullet.index > idx {
-                        bullet.index -= 1;
-                    });
-                    self.remove_bullet(idx);
+    /// Advances the simulation by one fixed tick of `delta_time` seconds.
+    /// The same state fed the same events at the same ticks always ends up
+    /// in the same place, no matter when the steps actually run.
+    pub fn step(&mut self, delta_time: f32) -> Vec<GameEvent> {
+        self.tick += 1;
+        let mut events = Vec::with_capacity(10);
 
+        for action in std::mem::take(&mut self.actions) {
+            match action {
+                Action::DeleteBullet(id) => {
+                    self.remove_bullet(id);
                 },
-                Action::DeletePlayer(name) => {
-                    self.kill_player(&name);
-                    events.push(GameEvent::Death(name));
+                Action::Hit { target, bullet: id } => {
+                    if !self.players.get(&target).is_some_and(|player| player.alive) {
+                        continue;
+                    }
+                    let Some(bullet) = self.remove_bullet(id) else {
+                        continue;
+                    };
+                    if !self.match_state.phase.allows_damage() {
+                        continue;
+                    }
+                    let Some(player) = self.players.get_mut(&target) else {
+                        continue;
+                    };
+                    let damage = player.damage_from(bullet.damage);
+                    let remaining = player.take_damage(damage);
+                    let shooter = bullet.shooter;
+                    events.push(GameEvent::PlayerHit { target: target.clone(), shooter: shooter.clone(), bullet: id, damage, remaining });
+                    if let Some(score) = self.scores.get_mut(&shooter) {
+                        score.damage_dealt += damage;
+                        score.shots_hit += 1;
+                        if remaining == 0 {
+                            score.kills += 1;
+                        }
+                    }
+                    if remaining == 0 {
+                        if matches!(self.match_state.phase, MatchPhase::InProgress { .. }) {
+                            *self.match_state.round_kills.entry(shooter.clone()).or_default() += 1;
+                        }
+                        self.kill_player(&target);
+                        if let Some(score) = self.scores.get_mut(&target) {
+                            score.deaths += 1;
+                            events.push(GameEvent::ScoreUpdate { name: target.clone(), score: score.clone() });
+                        }
+                        events.push(GameEvent::Death { name: target, killer: Some(shooter.clone()) });
+                    }
+                    if let Some(score) = self.scores.get(&shooter) {
+                        events.push(GameEvent::ScoreUpdate { name: shooter, score: score.clone() });
+                    }
                 }
             }
         }
 
-        self.players.iter_mut().for_each(|(_, state)| {
-            if let Some(act) = state.update(delta_time, &self.bullets) {
-                self.actions.push(act);
-            }
+        let no_respawns = self.match_state.rules.last_player_standing &&
+            matches!(self.match_state.phase, MatchPhase::InProgress { .. });
+        let due: Vec<String> = self.players.values()
+            .filter(|player| !no_respawns && !player.alive && player.respawn_tick <= self.tick)
+            .map(|player| player.name.clone())
+            .collect();
+        due.into_iter().for_each(|name| {
+            let position = self.spawn_point_for(&name);
+            events.push(GameEvent::Respawn { name: name.clone(), x: position.x, y: position.y });
+            self.respawn_player(&name, position);
+        });
+
+        self.update_match(&mut events);
+        self.spawn_pickups(delta_time, &mut events);
+
+        self.players.values_mut().for_each(|state| {
+            state.update(delta_time, &self.map);
+            state.update_weapons(delta_time, &self.weapons);
         });
+        self.separate_players();
 
-        self.bullets.iter_mut().for_each(|state: &mut BulletState| {
-            if let Some(act) = state.update(delta_time) {
-                self.actions.push(act);
+        // Bullets are swept from where they were to where they end up, so
+        // that fast ones can't skip over a player or a wall between ticks. A
+        // player only counts as hit if the bullet gets there before any wall,
+        // and hits are queued so that the earliest one is handled first.
+        let players: Vec<&PlayerState> = self.players.values().collect();
+        let grid = SpatialGrid::build(GRID_CELL_SIZE, players.iter().map(|state| &state.position));
+        let collected = self.pickups_reached(&players, &grid);
+        let mut hits = Vec::new();
+        self.bullets.iter_mut().for_each(|(id, state)| {
+            let from = state.position.clone();
+            let expired = state.update(id, delta_time);
+            let wall = self.map.first_block(&from, &state.position, BULLET_RADIUS_SIZE);
+            let hit = state.first_hit(&from, self.tick, &players, &grid)
+                .filter(|(t, _)| wall.is_none_or(|wall| *t <= wall));
+            match hit {
+                Some((t, target)) => hits.push((t, id, Action::Hit { target: target.name.clone(), bullet: id })),
+                None if wall.is_some() => self.actions.push(Action::DeleteBullet(id)),
+                None => self.actions.extend(expired)
             }
         });
-        
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        self.actions.extend(hits.into_iter().map(|(_, _, act)| act));
+        collected.into_iter().for_each(|(id, name)| self.collect_pickup(id, &name, &mut events));
+
         events
     }
 
+    /// Puts pickups back at their spawns once their respawn time is up.
+    /// Spawns that never had a pickup get one right away.
+    fn spawn_pickups(&mut self, delta_time: f32, events: &mut Vec<GameEvent>) {
+        self.pickup_respawns.values_mut().for_each(|secs| *secs -= delta_time);
+        let lying: Vec<usize> = self.pickups.values().map(|pickup| pickup.spawn).collect();
+        let map = self.map.clone();
+        map.pickups.iter().enumerate().for_each(|(spawn, point)| {
+            if lying.contains(&spawn) || self.pickup_respawns.get(&spawn).is_some_and(|secs| *secs > 0.0) {
+                return;
+            }
+            self.pickup_respawns.remove(&spawn);
+            let pickup = Pickup { kind: point.kind.clone(), position: point.position.clone(), spawn };
+            let id = self.pickups.insert(pickup.clone());
+            events.push(GameEvent::PickupSpawned { id, pickup });
+        });
+    }
+
+    /// Which living player, if any, gets each pickup: the first one by name
+    /// of those touching it that it does any good. `grid` holds `players` by
+    /// index.
+    fn pickups_reached(&self, players: &[&PlayerState], grid: &SpatialGrid) -> Vec<(EntityId, String)> {
+        let reach = PLAYER_RADIUS_SIZE + PICKUP_RADIUS_SIZE;
+        self.pickups.iter()
+            .filter_map(|(id, pickup)| {
+                grid.query(&pickup.position, reach)
+                    .filter(|&index| {
+                        let player = players[index];
+                        player.wants(&pickup.kind, &self.weapons) && player.position.distance_squared(&pickup.position) < reach.powi(2)
+                    })
+                    .min()
+                    .map(|index| (id, players[index].name.clone()))
+            })
+            .collect()
+    }
+
+    fn collect_pickup(&mut self, id: EntityId, name: &str, events: &mut Vec<GameEvent>) {
+        let Some(player) = self.players.get_mut(name) else {
+            return;
+        };
+        let Some(pickup) = self.pickups.remove(id) else {
+            return;
+        };
+        player.collect(&pickup.kind, &self.weapons);
+        let respawn_secs = self.map.pickups.get(pickup.spawn).map_or(0.0, |spawn| spawn.respawn_secs);
+        self.pickup_respawns.insert(pickup.spawn, respawn_secs);
+        events.push(GameEvent::PickupCollected { id, name: name.to_string() });
+    }
+
+    /// Pushes overlapping living players apart, each by half the overlap.
+    /// Only pairs sharing grid cells are looked at.
+    fn separate_players(&mut self) {
+        let (names, positions): (Vec<String>, Vec<Vec2>) = self.players.values()
+            .filter(|player| player.alive)
+            .map(|player| (player.name.clone(), player.position.clone()))
+            .unzip();
+        let grid = SpatialGrid::build(GRID_CELL_SIZE, &positions);
+        let min_distance = 2.0 * PLAYER_RADIUS_SIZE;
+        let mut pushes = vec![Vec2 { x: 0.0, y: 0.0 }; positions.len()];
+        for (i, position) in positions.iter().enumerate() {
+            for j in grid.query(position, min_distance).filter(|&j| j > i) {
+                let offset = Vec2 { x: positions[j].x - position.x, y: positions[j].y - position.y };
+                let distance = offset.length();
+                if distance >= min_distance {
+                    continue;
+                }
+                let direction = if distance > 0.0 { offset.scale(1.0 / distance) } else { Vec2 { x: 1.0, y: 0.0 } };
+                let push = direction.scale((min_distance - distance) / 2.0);
+                pushes[i] = pushes[i].sum(&push.scale(-1.0));
+                pushes[j] = pushes[j].sum(&push);
+            }
+        }
+        names.iter().zip(pushes).for_each(|(name, push)| {
+            if push.x == 0.0 && push.y == 0.0 {
+                return;
+            }
+            if let Some(player) = self.players.get_mut(name) {
+                player.position = self.map.resolve(&player.position.sum(&push), PLAYER_RADIUS_SIZE);
+            }
+        });
+    }
+
+    /// Moves the match along: starts countdowns once enough players are in,
+    /// starts rounds when they run out and ends rounds once a win condition
+    /// is met.
+    fn update_match(&mut self, events: &mut Vec<GameEvent>) {
+        let rules = &self.match_state.rules;
+        let enough_players = self.players.len() >= rules.min_players;
+        match self.match_state.phase.clone() {
+            MatchPhase::WaitingForPlayers => if enough_players {
+                let ends_at = self.tick.saturating_add(rules.countdown_ticks);
+                self.set_phase(MatchPhase::Countdown { ends_at }, events);
+            },
+            MatchPhase::Countdown { ends_at } => {
+                if !enough_players {
+                    self.set_phase(MatchPhase::WaitingForPlayers, events);
+                } else if self.tick >= ends_at {
+                    self.start_round(events);
+                }
+            },
+            MatchPhase::InProgress { ends_at, .. } => {
+                if let Some(winner) = self.round_winner(ends_at) {
+                    self.end_round(winner, events);
+                }
+            },
+            MatchPhase::RoundOver { ends_at, .. } => if self.tick >= ends_at {
+                let ends_at = self.tick.saturating_add(rules.countdown_ticks);
+                self.set_phase(MatchPhase::Countdown { ends_at }, events);
+            },
+            MatchPhase::MatchOver => {}
+        }
+    }
+
+    fn set_phase(&mut self, phase: MatchPhase, events: &mut Vec<GameEvent>) {
+        self.match_state.phase = phase.clone();
+        events.push(GameEvent::PhaseChanged(phase));
+    }
+
+    /// Puts everyone back at full health on a spawn point and clears the
+    /// field. The first round also wipes the scores of the warmup.
+    fn start_round(&mut self, events: &mut Vec<GameEvent>) {
+        self.match_state.round += 1;
+        if self.match_state.round == 1 {
+            self.match_state.rounds_won.clear();
+            self.scores.values_mut().for_each(|score| *score = Score::default());
+        }
+        self.match_state.round_kills.clear();
+        self.bullets.clear();
+        self.actions.clear();
+        // Pickups all come back for the new round.
+        self.pickups.clear();
+        self.pickup_respawns.clear();
+        let names: Vec<String> = self.players.keys().cloned().collect();
+        names.iter().for_each(|name| {
+            // `spawn_point_for` only keeps away from living players, so
+            // everyone goes down first and comes back one by one.
+            self.kill_player(name);
+        });
+        names.into_iter().for_each(|name| {
+            let position = self.spawn_point_for(&name);
+            events.push(GameEvent::Respawn { name: name.clone(), x: position.x, y: position.y });
+            self.respawn_player(&name, position);
+        });
+        let round = self.match_state.round;
+        let ends_at = self.match_state.rules.time_limit_ticks.map(|ticks| self.tick.saturating_add(ticks));
+        self.set_phase(MatchPhase::InProgress { round, ends_at }, events);
+    }
+
+    /// `Some(winner)` once the round is decided, where `winner` is `None`
+    /// for a draw.
+    fn round_winner(&self, ends_at: Option<u64>) -> Option<Option<String>> {
+        let rules = &self.match_state.rules;
+        let kills = &self.match_state.round_kills;
+        if let Some(limit) = rules.frag_limit {
+            if let Some((name, _)) = kills.iter().find(|(_, kills)| **kills >= limit) {
+                return Some(Some(name.clone()));
+            }
+        }
+        let alive: Vec<&String> = self.players.values().filter(|player| player.alive).map(|player| &player.name).collect();
+        if self.players.len() < rules.min_players || (rules.last_player_standing && alive.len() <= 1) {
+            return Some(alive.first().map(|name| name.to_string()));
+        }
+        if ends_at.is_some_and(|ends_at| self.tick >= ends_at) {
+            let best = kills.values().max().copied().unwrap_or(0);
+            let mut leaders = kills.iter().filter(|(_, kills)| **kills == best && best > 0);
+            return Some(match (leaders.next(), leaders.next()) {
+                (Some((name, _)), None) => Some(name.clone()),
+                _ => None
+            });
+        }
+        None
+    }
+
+    fn end_round(&mut self, winner: Option<String>, events: &mut Vec<GameEvent>) {
+        if let Some(name) = &winner {
+            *self.match_state.rounds_won.entry(name.clone()).or_default() += 1;
+        }
+        let round = self.match_state.round;
+        if round >= self.match_state.rules.rounds {
+            self.set_phase(MatchPhase::MatchOver, events);
+            events.push(GameEvent::MatchResults(self.results()));
+        } else {
+            let ends_at = self.tick.saturating_add(self.match_state.rules.round_over_ticks);
+            self.set_phase(MatchPhase::RoundOver { round, winner, ends_at }, events);
+        }
+    }
+
+    pub fn results(&self) -> MatchResults {
+        let mut standings: Vec<Standing> = self.scores.iter().map(|(name, score)| Standing {
+            name: name.clone(),
+            rounds_won: self.match_state.rounds_won.get(name).copied().unwrap_or(0),
+            score: score.clone()
+        }).collect();
+        standings.sort_by(|a, b| b.rounds_won.cmp(&a.rounds_won)
+            .then(b.score.kills.cmp(&a.score.kills))
+            .then(a.score.deaths.cmp(&b.score.deaths))
+            .then_with(|| a.name.cmp(&b.name)));
+        let winner = match standings.as_slice() {
+            [first, second, ..] if first.rounds_won == second.rounds_won => None,
+            [first, ..] if first.rounds_won > 0 => Some(first.name.clone()),
+            _ => None
+        };
+        MatchResults { winner, standings }
+    }
+
     pub fn kill_player(&mut self, name: &str) {
+        let respawn_tick = self.tick.saturating_add(self.respawn.delay_ticks);
+        if let Some(player) = self.players.get_mut(name) {
+            player.die(respawn_tick);
+        }
+    }
+
+    pub fn respawn_player(&mut self, name: &str, position: Vec2) {
+        let protected_until = self.tick.saturating_add(self.respawn.protection_ticks);
+        if let Some(player) = self.players.get_mut(name) {
+            player.respawn(position, protected_until, &self.weapons);
+        }
+    }
+
+    /// The spawn point farthest from the nearest living player other than
+    /// `name`. Without any, spawn points are taken in turn by tick.
+    pub fn spawn_point_for(&self, name: &str) -> Vec2 {
+        let enemies: Vec<&Vec2> = self.players.values()
+            .filter(|player| player.alive && player.name != name)
+            .map(|player| &player.position)
+            .collect();
+        let spawn_points = &self.map.spawn_points;
+        if spawn_points.is_empty() {
+            return Vec2 { x: 0.0, y: 0.0 };
+        }
+        if enemies.is_empty() {
+            return spawn_points[self.tick as usize % spawn_points.len()].clone();
+        }
+        let nearest_enemy = |point: &Vec2| enemies.iter()
+            .map(|enemy| point.distance_squared(enemy))
+            .fold(f32::INFINITY, f32::min);
+        spawn_points.iter()
+            .fold(None, |best: Option<(&Vec2, f32)>, point| {
+                let distance = nearest_enemy(point);
+                match best {
+                    Some((_, best_distance)) if best_distance >= distance => best,
+                    _ => Some((point, distance))
+                }
+            })
+            .map(|(point, _)| point.clone())
+            .unwrap_or(Vec2 { x: 0.0, y: 0.0 })
+    }
+
+    pub fn remove_player(&mut self, name: &str) {
         self.players.remove(name);
     }
 
-    pub fn remove_bullet(&mut self, index: usize) {
-        self.bullets.remove(index);
+    pub fn remove_bullet(&mut self, id: EntityId) -> Option<BulletState> {
+        self.bullets.remove(id)
     }
 
-    pub fn add_bullet(&mut self, pos: Vec2, vel: Vec2){
-        self.bullets.push(BulletState { position: pos, velocity: vel, lifetime: 10.0, 
-            time: 0.0, index: self.bullets.len() })
+    /// Adds a plain bullet of `BULLET_DAMAGE` that flies for 10 seconds.
+    pub fn add_bullet(&mut self, pos: Vec2, vel: Vec2, shooter: &str) -> EntityId {
+        self.bullets.insert(BulletState::new(pos, vel, shooter, BULLET_DAMAGE, 10.0))
+    }
+
+    /// Fires `name`'s weapon in hand, if it is ready, and returns how many
+    /// projectiles came out.
+    pub fn fire(&mut self, name: &str) -> u32 {
+        let Some(player) = self.players.get_mut(name).filter(|player| player.alive) else {
+            return 0;
+        };
+        let Some(state) = player.weapons.get_mut(player.current_weapon).filter(|state| state.can_fire()) else {
+            return 0;
+        };
+        let Some(weapon) = self.weapons.get(state.weapon) else {
+            return 0;
+        };
+        state.fire(weapon);
+        let position = player.position.sum(&Vec2::with_angle(player.angle, PLAYER_RADIUS_SIZE + 1.0));
+        let bullets: Vec<BulletState> = weapon.pellet_angles(player.angle)
+            .map(|angle| BulletState::new(position.clone(), Vec2::with_angle(angle, weapon.projectile_speed), name,
+                weapon.damage, weapon.projectile_lifetime_secs))
+            .collect();
+        let fired = bullets.len() as u32;
+        bullets.into_iter().for_each(|bullet| { self.bullets.insert(bullet); });
+        fired
     }
 
     pub fn add_player(&mut self, name: &str, pos: Vec2){
         self.players.insert(name.to_string(), PlayerState { name: name.to_string(), position: pos, velocity: Vec2 { x: 0.0, y: 0.0 }, 
-            angle: 0.0, health: 100, alive: true});
+            angle: 0.0, health: PLAYER_MAX_HEALTH, alive: true, respawn_tick: 0, protected_until: 0, last_input_seq: 0,
+            weapons: Vec::new(), current_weapon: 0, effects: Vec::new() });
+        if let Some(player) = self.players.get_mut(name) {
+            player.arm(&self.weapons);
+        }
+        self.scores.entry(name.to_string()).or_default();
+    }
+
+    /// Applies a player's input, marking its sequence number as processed
+    /// for the player's prediction. Returns whether it changed the game, and
+    /// so needs relaying to the other players: inputs of dead or unknown
+    /// players, and shots the weapon isn't ready for, don't.
+    pub fn apply_input(&mut self, input: PlayerInput) -> bool {
+        if let Some(player) = self.players.get_mut(&input.name) {
+            player.last_input_seq = player.last_input_seq.max(input.seq);
+        }
+        self.apply_player_event(input.event)
+    }
+
+    /// Applies one of the events players send, returning whether it changed
+    /// anything. Events for players that aren't (or are no longer) in the
+    /// game are dropped, as are inputs of dead players.
+    fn apply_player_event(&mut self, event: GameEvent) -> bool {
+        match event {
+            // Also dropped while the weapon cools down or reloads.
+            GameEvent::Shooting(name) => self.shoot(&name) > 0,
+            GameEvent::SwitchWeapon { slot, name } => {
+                let player = self.players.get_mut(&name).filter(|player| player.alive && slot < player.weapons.len());
+                player.map(|player| player.current_weapon = slot).is_some()
+            },
+            GameEvent::UpdateAngle { angle, name } => {
+                let player = self.players.get_mut(&name).filter(|player| player.alive);
+                player.map(|player| player.angle = angle).is_some()
+            },
+            GameEvent::UpdateVelocity { x, y, name } => {
+                let player = self.players.get_mut(&name).filter(|player| player.alive);
+                player.map(|player| player.velocity = Vec2 { x, y }).is_some()
+            },
+            _ => false
+        }
+    }
+
+    /// Fires the player's weapon and counts the shots. Returns how many
+    /// bullets it fired.
+    fn shoot(&mut self, name: &str) -> u32 {
+        let fired = self.fire(name);
+        if let Some(score) = self.scores.get_mut(name) {
+            score.shots_fired += fired;
+        }
+        fired
     }
 
     pub fn react_to_event(&mut self, event: GameEvent) {
         match event {
             GameEvent::AddPlayer { x, y, name } => {
-                self.add_player(&name, Vec2 { x: x, y: y });
+                self.add_player(&name, Vec2 { x, y });
+            },
+            GameEvent::PlayerHit { target, remaining, .. } => {
+                if let Some(player) = self.players.get_mut(&target) {
+                    player.health = remaining;
+                }
             },
-            GameEvent::Death(name) => {
+            GameEvent::Death { name, .. } => {
                 self.kill_player(&name);
             },
-            GameEvent::Shooting(name) => {
-                let player = self.players.get(&name).unwrap();
-                self.add_bullet(player.position.sum(&Vec2::with_angle(player.angle, 
-                    PLAYER_RADIUS_SIZE + 1.0)), Vec2::with_angle(player.angle, BULLET_VEL));
+            GameEvent::ScoreUpdate { name, score } => {
+                self.scores.insert(name, score);
             },
-            GameEvent::UpdateAngle { angle, name } => {
-                let player = self.players.get_mut(&name).unwrap();
-                player.angle = angle;
+            GameEvent::PhaseChanged(phase) => {
+                self.match_state.phase = phase;
             },
-            GameEvent::UpdateVelocity { x, y, name } => {
-                let player = self.players.get_mut(&name).unwrap();
-                player.velocity.x = x;
-                player.velocity.y = y;
+            GameEvent::MatchResults(_) => {},
+            GameEvent::Respawn { name, x, y } => {
+                self.respawn_player(&name, Vec2 { x, y });
+            },
+            GameEvent::PlayerLeft(name) => {
+                self.remove_player(&name);
+            },
+            GameEvent::PickupSpawned { id, pickup } => {
+                self.pickups.insert_at(id, pickup);
+            },
+            GameEvent::PickupCollected { id, .. } => {
+                self.pickups.remove(id);
+            },
+            event @ (GameEvent::Shooting(_) | GameEvent::SwitchWeapon { .. } | GameEvent::UpdateAngle { .. } | GameEvent::UpdateVelocity { .. }) => {
+                self.apply_player_event(event);
             },
+            GameEvent::Error(_) | GameEvent::LobbyClosed | GameEvent::ServerShutdown { .. } => {},
             GameEvent::GameStateSync(gm) => {
                 *self = gm;
 
+            },
+            GameEvent::GameStateDelta(_) => {
+                // Deltas only make sense against the snapshot they were taken
+                // from; `snapshot::DeltaDecoder` turns them back into syncs.
             }
         }
     }
@@ -185,35 +998,541 @@ impl GameState {
 mod tests {
 
     use super::*;
+    use crate::pickup::PickupSpawn;
+    use crate::map::Obstacle;
 
     #[test]
     fn test() {
         let mut game = GameState {
-            players: HashMap::from_iter([("pl".to_string(),
+            players: BTreeMap::from_iter([("pl".to_string(),
                 PlayerState {
                     position: Vec2 { x: 100.0, y: 100.0 },
                     velocity: Vec2 { x: 10.0, y: 0.0 },
                     angle: 0.0,
                     name: "pl".to_string().clone(),
                     health: 100,
-                    alive: true
+                    alive: true,
+                    respawn_tick: 0,
+                    protected_until: 0,
+                    last_input_seq: 0,
+                    weapons: Vec::new(),
+                    current_weapon: 0,
+                    effects: Vec::new()
                 }),
             ]),
-            bullets: Vec::with_capacity(50),
-            last_time: time_util::get_current_time(),
-            actions: Vec::with_capacity(10)
-    
+            ..GameState::new()
         };
 
-        game.add_bullet(Vec2 { x: 0.0, y: 0.0 }, Vec2 { x: BULLET_VEL, y: 0.0 });
+        let bullet = game.add_bullet(Vec2 { x: 20.0, y: 20.0 }, Vec2 { x: BULLET_VEL, y: 0.0 }, "pl");
 
-        game.update(time_util::get_current_time());
-        println!("{:?}", game);
-        std::thread::sleep(std::time::Duration::from_millis(25));
-        game.update(time_util::get_current_time());
-        println!("{:?}", game);
+        game.step(0.025);
+        game.step(0.025);
+        assert_eq!(game.tick, 2);
+        assert!((game.players["pl"].position.x - 100.5).abs() < 1e-4);
+        assert!((game.bullets.get(bullet).unwrap().position.x - 20.0 - BULLET_VEL * 0.05).abs() < 1e-4);
+    }
 
+    fn run(inputs: &[(u64, GameEvent)], ticks: u64) -> GameState {
+        let mut game = GameState::new();
+        game.add_player("a", Vec2 { x: 0.0, y: 0.0 });
+        game.add_player("b", Vec2 { x: 200.0, y: 50.0 });
+        while game.tick < ticks {
+            let tick = game.tick;
+            inputs.iter()
+                .filter(|(at, _)| *at == tick)
+                .for_each(|(_, event)| game.react_to_event(event.clone()));
+            game.step(TICK_DT);
+        }
+        game
+    }
 
+    #[test]
+    fn angles_point_where_players_aim() {
+        let close = |a: Vec2, x: f32, y: f32| (a.x - x).abs() < 1e-5 && (a.y - y).abs() < 1e-5;
+        assert!(close(Vec2::with_angle(0.0, 1.0), 1.0, 0.0));
+        assert!(close(Vec2::with_angle(90.0, 1.0), 0.0, 1.0));
+        assert!(close(Vec2::with_angle(180.0, 2.0), -2.0, 0.0));
+        assert!(close(Vec2::with_angle(270.0, 1.0), 0.0, -1.0));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn same_inputs_at_same_ticks_give_same_state() {
+        let inputs = vec![
+            (0, GameEvent::UpdateVelocity { x: 30.0, y: 0.0, name: "a".to_string() }),
+            (3, GameEvent::UpdateAngle { angle: 45.0, name: "b".to_string() }),
+            (5, GameEvent::Shooting("b".to_string())),
+            (20, GameEvent::UpdateVelocity { x: 0.0, y: -12.5, name: "a".to_string() }),
+        ];
+        let first = run(&inputs, 90);
+        let second = run(&inputs, 90);
+        assert_eq!(first.tick, 90);
+        assert_eq!(serde_json::to_string(&first).unwrap(), serde_json::to_string(&second).unwrap());
+    }
+
+    #[test]
+    fn events_for_unknown_players_are_ignored() {
+        let mut game = GameState::new();
+        game.add_player("pl", Vec2 { x: 0.0, y: 0.0 });
+        game.react_to_event(GameEvent::Shooting("ghost".to_string()));
+        game.react_to_event(GameEvent::UpdateAngle { angle: 90.0, name: "ghost".to_string() });
+        game.react_to_event(GameEvent::UpdateVelocity { x: 1.0, y: 1.0, name: "ghost".to_string() });
+        game.react_to_event(GameEvent::Error("bad input".to_string()));
+        assert!(game.bullets.is_empty());
+        assert_eq!(game.players.len(), 1);
+
+        // Inputs that change nothing aren't worth relaying.
+        let input = |name: &str, event: GameEvent| PlayerInput { name: name.to_string(), seq: 1, event };
+        assert!(game.apply_input(input("pl", GameEvent::UpdateAngle { angle: 90.0, name: "pl".to_string() })));
+        assert!(!game.apply_input(input("ghost", GameEvent::UpdateAngle { angle: 90.0, name: "ghost".to_string() })));
+        assert!(!game.apply_input(input("pl", GameEvent::SwitchWeapon { slot: 99, name: "pl".to_string() })));
+        game.kill_player("pl");
+        assert!(!game.apply_input(input("pl", GameEvent::UpdateVelocity { x: 1.0, y: 1.0, name: "pl".to_string() })));
+    }
+
+    #[test]
+    fn hits_take_health_and_leave_a_corpse() {
+        let mut game = GameState::new();
+        game.add_player("target", Vec2 { x: 100.0, y: 100.0 });
+        let mut events = Vec::new();
+        for _ in 0..PLAYER_MAX_HEALTH / BULLET_DAMAGE {
+            game.add_bullet(Vec2 { x: 100.0, y: 100.0 }, Vec2 { x: 0.0, y: 0.0 }, "shooter");
+            events.extend(game.step(TICK_DT));
+            events.extend(game.step(TICK_DT));
+            assert!(game.bullets.is_empty());
+        }
+        let hits: Vec<i32> = events.iter().filter_map(|event| match event {
+            GameEvent::PlayerHit { target, shooter, damage, remaining, .. } => {
+                assert_eq!((target.as_str(), shooter.as_str(), *damage), ("target", "shooter", BULLET_DAMAGE));
+                Some(*remaining)
+            },
+            _ => None
+        }).collect();
+        assert_eq!(hits, vec![75, 50, 25, 0]);
+        assert!(matches!(events.iter().find(|event| matches!(event, GameEvent::Death { .. })),
+            Some(GameEvent::Death { name, killer: Some(killer) }) if name == "target" && killer == "shooter"));
+
+        let corpse = &game.players["target"];
+        assert!(!corpse.alive);
+        assert_eq!(corpse.health, 0);
+
+        // Corpses don't stop bullets or react to inputs.
+        game.add_bullet(Vec2 { x: 100.0, y: 100.0 }, Vec2 { x: 0.0, y: 0.0 }, "shooter");
+        game.react_to_event(GameEvent::UpdateVelocity { x: 10.0, y: 0.0, name: "target".to_string() });
+        assert!(game.step(TICK_DT).is_empty());
+        assert!(game.step(TICK_DT).is_empty());
+        assert_eq!(game.bullets.len(), 1);
+        assert_eq!(game.players["target"].velocity, Vec2 { x: 0.0, y: 0.0 });
+    }
+
+    #[test]
+    fn dead_players_respawn_away_from_enemies_and_are_protected() {
+        let mut game = GameState::new();
+        game.respawn = RespawnRules::from_secs(1.0, 0.5, TICK_RATE);
+        game.map = Arc::new(Map {
+            spawn_points: vec![Vec2 { x: 20.0, y: 20.0 }, Vec2 { x: 500.0, y: 20.0 }, Vec2 { x: 80.0, y: 20.0 }],
+            ..Map::default()
+        });
+        game.match_state.rules.min_players = 3;
+        game.add_player("enemy", Vec2 { x: 50.0, y: 0.0 });
+        game.add_player("pl", Vec2 { x: 300.0, y: 0.0 });
+        game.react_to_event(GameEvent::Death { name: "pl".to_string(), killer: None });
+
+        let mut respawned = None;
+        for _ in 0..TICK_RATE {
+            assert!(!game.players["pl"].alive);
+            respawned = game.step(TICK_DT).into_iter().find(|event| matches!(event, GameEvent::Respawn { .. }));
+        }
+        assert!(matches!(respawned, Some(GameEvent::Respawn { name, x, y }) if name == "pl" && x == 500.0 && y == 20.0));
+        let player = &game.players["pl"];
+        assert!(player.alive);
+        assert_eq!(player.health, PLAYER_MAX_HEALTH);
+        assert!(player.is_protected(game.tick));
+
+        // Bullets pass through protected players.
+        game.add_bullet(Vec2 { x: 500.0, y: 20.0 }, Vec2 { x: 0.0, y: 0.0 }, "enemy");
+        game.step(TICK_DT);
+        game.step(TICK_DT);
+        assert_eq!(game.players["pl"].health, PLAYER_MAX_HEALTH);
+        while game.players["pl"].is_protected(game.tick) {
+            game.step(TICK_DT);
+        }
+        game.step(TICK_DT);
+        game.step(TICK_DT);
+        assert_eq!(game.players["pl"].health, PLAYER_MAX_HEALTH - BULLET_DAMAGE);
+    }
+
+    #[test]
+    fn kills_and_hits_are_scored() {
+        let mut game = GameState::new();
+        game.add_player("shooter", Vec2 { x: 50.0, y: 50.0 });
+        game.add_player("target", Vec2 { x: 150.0, y: 150.0 });
+        game.players.get_mut("target").unwrap().health = BULLET_DAMAGE;
+        // Stay in the warmup, so that the match start doesn't reset anything.
+        game.match_state.rules.min_players = 3;
+
+        // A miss, then a hit that kills.
+        game.react_to_event(GameEvent::UpdateAngle { angle: 225.0, name: "shooter".to_string() });
+        game.react_to_event(GameEvent::Shooting("shooter".to_string()));
+        // Wait out the cooldown.
+        for _ in 0..TICK_RATE / 2 {
+            game.step(TICK_DT);
+        }
+        game.react_to_event(GameEvent::UpdateAngle { angle: 45.0, name: "shooter".to_string() });
+        game.react_to_event(GameEvent::Shooting("shooter".to_string()));
+        let mut updates = Vec::new();
+        // Long enough for the miss to run out too.
+        for _ in 0..11 * TICK_RATE {
+            updates.extend(game.step(TICK_DT).into_iter().filter(|event| matches!(event, GameEvent::ScoreUpdate { .. })));
+        }
+
+        let shooter = &game.scores["shooter"];
+        assert_eq!((shooter.kills, shooter.deaths, shooter.damage_dealt), (1, 0, BULLET_DAMAGE));
+        assert_eq!((shooter.shots_fired, shooter.shots_hit), (2, 1));
+        assert_eq!(shooter.accuracy(), 0.5);
+        assert_eq!(game.scores["target"].deaths, 1);
+        assert_eq!(updates.len(), 2);
+
+        // Players can't shoot themselves, even standing in their own bullet.
+        game.add_bullet(Vec2 { x: 50.0, y: 50.0 }, Vec2 { x: 0.0, y: 0.0 }, "shooter");
+        game.step(TICK_DT);
+        game.step(TICK_DT);
+        assert_eq!(game.players["shooter"].health, PLAYER_MAX_HEALTH);
+        assert_eq!(game.bullets.len(), 1);
+    }
+
+    #[test]
+    fn players_slide_along_walls_and_bullets_stop_at_obstacles() {
+        let mut game = GameState::new();
+        game.map = Arc::new(Map {
+            obstacles: vec![Obstacle::Rect { x: 200.0, y: 0.0, width: 50.0, height: 600.0 }],
+            ..Map::default()
+        });
+        game.add_player("pl", Vec2 { x: 150.0, y: 100.0 });
+        game.react_to_event(GameEvent::UpdateVelocity { x: PLAYER_MAX_VEL, y: PLAYER_MAX_VEL, name: "pl".to_string() });
+        for _ in 0..3 * TICK_RATE {
+            game.step(TICK_DT);
+        }
+        let player = &game.players["pl"];
+        assert!((player.position.x - (200.0 - PLAYER_RADIUS_SIZE)).abs() < 1e-3);
+        assert!((player.position.y - (100.0 + 3.0 * PLAYER_MAX_VEL)).abs() < 1e-2);
+
+        game.add_bullet(Vec2 { x: 180.0, y: 400.0 }, Vec2 { x: BULLET_VEL, y: 0.0 }, "pl");
+        for _ in 0..TICK_RATE {
+            game.step(TICK_DT);
+        }
+        assert!(game.bullets.is_empty());
+    }
+
+    #[test]
+    fn players_push_each_other_apart() {
+        let mut game = GameState::new();
+        game.add_player("a", Vec2 { x: 200.0, y: 200.0 });
+        game.add_player("b", Vec2 { x: 205.0, y: 200.0 });
+        game.add_player("c", Vec2 { x: 200.0, y: 200.0 });
+        game.add_player("far", Vec2 { x: 600.0, y: 400.0 });
+        for _ in 0..10 {
+            game.step(TICK_DT);
+        }
+        let players: Vec<&PlayerState> = game.players.values().collect();
+        for (i, a) in players.iter().enumerate() {
+            for b in &players[i + 1..] {
+                assert!(a.position.distance_squared(&b.position) >= (2.0 * PLAYER_RADIUS_SIZE - 0.5).powi(2), "{} and {} overlap", a.name, b.name);
+            }
+        }
+        assert_eq!(game.players["far"].position, Vec2 { x: 600.0, y: 400.0 });
+
+        // Corpses don't get in the way.
+        game.kill_player("c");
+        let corpse = game.players["c"].position.clone();
+        game.players.get_mut("a").unwrap().position = corpse.clone();
+        game.step(TICK_DT);
+        assert_eq!(game.players["a"].position, corpse);
+    }
+
+    #[test]
+    fn fast_bullets_hit_what_they_pass_first() {
+        let mut game = GameState::new();
+        game.match_state.rules.min_players = 5;
+        game.add_player("near", Vec2 { x: 400.0, y: 300.0 });
+        game.add_player("far", Vec2 { x: 450.0, y: 300.0 });
+        game.add_player("pl", Vec2 { x: 100.0, y: 100.0 });
+        // Goes from one side of both players to the other within a tick.
+        game.add_bullet(Vec2 { x: 300.0, y: 300.0 }, Vec2 { x: 250.0 / TICK_DT, y: 0.0 }, "pl");
+        let mut events = game.step(TICK_DT);
+        events.extend(game.step(TICK_DT));
+        let hits: Vec<&str> = events.iter().filter_map(|event| match event {
+            GameEvent::PlayerHit { target, .. } => Some(target.as_str()),
+            _ => None
+        }).collect();
+        assert_eq!(hits, ["near"]);
+        assert!(game.bullets.is_empty());
+
+        // Of two lethal bullets, the one that gets there first takes the kill.
+        game.add_player("other", Vec2 { x: 100.0, y: 500.0 });
+        game.players.get_mut("near").unwrap().position = Vec2 { x: 600.0, y: 100.0 };
+        game.players.get_mut("far").unwrap().health = BULLET_DAMAGE;
+        game.add_bullet(Vec2 { x: 350.0, y: 300.0 }, Vec2 { x: 150.0 / TICK_DT, y: 0.0 }, "pl");
+        game.add_bullet(Vec2 { x: 445.0, y: 250.0 }, Vec2 { x: 0.0, y: 100.0 / TICK_DT }, "other");
+        let mut events = game.step(TICK_DT);
+        events.extend(game.step(TICK_DT));
+        assert!(events.iter().any(|event| matches!(event, GameEvent::Death { name, killer: Some(killer) } if name == "far" && killer == "other")));
+        assert_eq!(game.scores["other"].kills, 1);
+        assert_eq!(game.scores["pl"].shots_hit, 1);
+    }
+
+    #[test]
+    fn very_fast_projectiles_are_swept_cheaply() {
+        let mut game = GameState::new();
+        game.match_state.rules.min_players = 5;
+        // Crosses the whole arena and far beyond within one tick.
+        game.weapons = Arc::new(vec![Weapon { projectile_speed: 1e9, ..Weapon::rifle() }]);
+        game.add_player("pl", Vec2 { x: 100.0, y: 300.0 });
+        game.add_player("target", Vec2 { x: 700.0, y: 300.0 });
+        assert_eq!(game.fire("pl"), 1);
+        let mut events = game.step(TICK_DT);
+        events.extend(game.step(TICK_DT));
+        assert!(events.iter().any(|event| matches!(event, GameEvent::PlayerHit { target, .. } if target == "target")));
+        assert!(game.bullets.is_empty());
+    }
+
+    #[test]
+    fn fast_bullets_stop_at_walls_they_pass_through() {
+        let mut game = GameState::new();
+        game.map = Arc::new(Map { obstacles: vec![Obstacle::Rect { x: 400.0, y: 250.0, width: 4.0, height: 100.0 }], ..Map::default() });
+        game.match_state.rules.min_players = 5;
+        game.add_player("behind", Vec2 { x: 450.0, y: 300.0 });
+        game.add_player("pl", Vec2 { x: 100.0, y: 100.0 });
+        // Starts and ends clear of the wall, and would reach the player
+        // within the same tick.
+        let bullet = game.add_bullet(Vec2 { x: 380.0, y: 300.0 }, Vec2 { x: 66.0 / TICK_DT, y: 0.0 }, "pl");
+        let mut events = game.step(TICK_DT);
+        events.extend(game.step(TICK_DT));
+        assert!(!events.iter().any(|event| matches!(event, GameEvent::PlayerHit { .. })));
+        assert!(!game.bullets.contains(bullet));
+        assert_eq!(game.players["behind"].health, PLAYER_MAX_HEALTH);
+    }
+
+    #[test]
+    fn removing_several_bullets_keeps_the_right_ones() {
+        let mut game = GameState::new();
+        let ids: Vec<EntityId> = (0..6).map(|i| {
+            let y = 100.0 + 50.0 * i as f32;
+            // Every other bullet flies into the right wall on the first step.
+            let velocity = if i % 2 == 0 { Vec2 { x: 100.0 / TICK_DT, y: 0.0 } } else { Vec2 { x: 0.0, y: 0.0 } };
+            game.add_bullet(Vec2 { x: 750.0, y }, velocity, "pl")
+        }).collect();
+        game.step(TICK_DT);
+        game.step(TICK_DT);
+        let left: Vec<EntityId> = game.bullets.ids().collect();
+        assert_eq!(left, [ids[1], ids[3], ids[5]]);
+        assert!(left.iter().all(|id| game.bullets.get(*id).unwrap().position.x == 750.0));
+
+        // Freed slots are reused, but old IDs don't find the new bullets.
+        let new = game.add_bullet(Vec2 { x: 100.0, y: 100.0 }, Vec2 { x: 0.0, y: 0.0 }, "pl");
+        assert!(ids.iter().all(|id| *id != new));
+        assert!(game.bullets.get(ids[4]).is_none());
+    }
+
+    #[test]
+    fn weapons_have_cooldowns_ammo_and_pellets() {
+        let mut game = GameState::new();
+        game.weapons = Arc::new(vec![Weapon { magazine: 2, ..Weapon::pistol() }, Weapon::shotgun()]);
+        game.add_player("pl", Vec2 { x: 100.0, y: 300.0 });
+        let shoot = |game: &mut GameState| game.apply_input(PlayerInput { name: "pl".to_string(), seq: 0, event: GameEvent::Shooting("pl".to_string()) });
+        let wait = |game: &mut GameState, secs: f32| for _ in 0..(secs * TICK_RATE as f32).ceil() as u32 {
+            game.step(TICK_DT);
+        };
+
+        assert!(shoot(&mut game));
+        assert!(!shoot(&mut game), "rejected shots aren't passed on");
+        assert_eq!(game.bullets.len(), 1, "the second shot is within the cooldown");
+        wait(&mut game, 0.25);
+        shoot(&mut game);
+        assert_eq!(game.players["pl"].weapon().unwrap().ammo, 0);
+        wait(&mut game, 0.25);
+        shoot(&mut game);
+        assert_eq!(game.scores["pl"].shots_fired, 2, "the magazine is empty");
+        wait(&mut game, 1.5);
+        assert_eq!(game.players["pl"].weapon().unwrap().ammo, 2);
+
+        // Shotgun pellets fan out around the aim, which is straight down.
+        game.react_to_event(GameEvent::UpdateAngle { angle: 90.0, name: "pl".to_string() });
+        game.react_to_event(GameEvent::SwitchWeapon { slot: 1, name: "pl".to_string() });
+        game.react_to_event(GameEvent::SwitchWeapon { slot: 2, name: "pl".to_string() });
+        assert_eq!(game.players["pl"].current_weapon, 1);
+        let before: Vec<EntityId> = game.bullets.ids().collect();
+        shoot(&mut game);
+        let pellets: Vec<&BulletState> = game.bullets.iter().filter(|(id, _)| !before.contains(id)).map(|(_, bullet)| bullet).collect();
+        assert_eq!(pellets.len(), 6);
+        assert!(pellets.iter().all(|pellet| pellet.damage == 10 && pellet.velocity.y > 0.0));
+        assert!(pellets.iter().any(|pellet| pellet.velocity.x < 0.0) && pellets.iter().any(|pellet| pellet.velocity.x > 0.0));
+        assert_eq!(game.scores["pl"].shots_fired, 8);
+
+        // Dying and coming back reloads everything.
+        game.kill_player("pl");
+        game.respawn_player("pl", Vec2 { x: 100.0, y: 300.0 });
+        assert_eq!(game.players["pl"].current_weapon, 0);
+        assert_eq!(game.players["pl"].weapons.iter().map(|state| state.ammo).collect::<Vec<u32>>(), [2, 4]);
+    }
+
+    #[test]
+    fn pickups_are_collected_and_come_back() {
+        let spawn = |x: f32, y: f32, kind: PickupKind| PickupSpawn { position: Vec2 { x, y }, kind, respawn_secs: 1.0 };
+        let mut game = GameState::new();
+        game.map = Arc::new(Map { pickups: vec![
+            spawn(200.0, 300.0, PickupKind::Health { amount: 50 }),
+            spawn(400.0, 300.0, PickupKind::SpeedBoost { secs: 0.5 }),
+            spawn(600.0, 300.0, PickupKind::Shield { secs: 0.5 }),
+            spawn(300.0, 100.0, PickupKind::WeaponCrate { weapon: "shotgun".to_string() })
+        ], ..Map::default() });
+        game.add_player("pl", Vec2 { x: 100.0, y: 500.0 });
+        let events = game.step(TICK_DT);
+        let spawned: Vec<EntityId> = events.iter().filter_map(|event| match event {
+            GameEvent::PickupSpawned { id, .. } => Some(*id),
+            _ => None
+        }).collect();
+        assert_eq!(spawned.len(), 4);
+        let collected = |events: &[GameEvent]| events.iter().filter_map(|event| match event {
+            GameEvent::PickupCollected { id, name } if name == "pl" => Some(*id),
+            _ => None
+        }).collect::<Vec<EntityId>>();
+        let go_to = |game: &mut GameState, x: f32, y: f32| {
+            game.players.get_mut("pl").unwrap().position = Vec2 { x, y };
+            game.step(TICK_DT)
+        };
+
+        // Health packs are left alone at full health.
+        assert!(collected(&go_to(&mut game, 200.0, 300.0)).is_empty());
+        game.players.get_mut("pl").unwrap().health = 70;
+        assert_eq!(collected(&go_to(&mut game, 200.0, 300.0)), [spawned[0]]);
+        assert_eq!(game.players["pl"].health, PLAYER_MAX_HEALTH);
+        assert_eq!(game.pickups.len(), 3);
+
+        // Weapon crates are left alone while that weapon is full.
+        assert!(collected(&go_to(&mut game, 300.0, 100.0)).is_empty());
+        game.players.get_mut("pl").unwrap().weapons[1].ammo = 0;
+        assert_eq!(collected(&go_to(&mut game, 300.0, 100.0)), [spawned[3]]);
+        assert_eq!(game.players["pl"].current_weapon, 1);
+        assert_eq!(game.players["pl"].weapons[1].ammo, Weapon::shotgun().magazine);
+        let unknown = PickupKind::WeaponCrate { weapon: "railgun".to_string() };
+        assert!(!game.players["pl"].wants(&unknown, &game.weapons));
+
+        // Speed boosts make players faster until they wear off.
+        assert_eq!(collected(&go_to(&mut game, 400.0, 300.0)), [spawned[1]]);
+        game.players.get_mut("pl").unwrap().velocity = Vec2 { x: 30.0, y: 0.0 };
+        game.step(TICK_DT);
+        assert!((game.players["pl"].position.x - 400.0 - 30.0 * SPEED_BOOST_FACTOR * TICK_DT).abs() < 1e-3);
+        game.players.get_mut("pl").unwrap().velocity = Vec2 { x: 0.0, y: 0.0 };
+
+        // Shields take off part of the damage.
+        assert_eq!(collected(&go_to(&mut game, 600.0, 300.0)), [spawned[2]]);
+        assert!(game.players["pl"].has_effect(EffectKind::Shield));
+        game.add_bullet(Vec2 { x: 600.0, y: 300.0 }, Vec2 { x: 0.0, y: 0.0 }, "other");
+        game.step(TICK_DT);
+        let events = game.step(TICK_DT);
+        assert!(events.iter().any(|event| matches!(event, GameEvent::PlayerHit { damage: 13, remaining: 87, .. })));
+
+        go_to(&mut game, 100.0, 500.0);
+        for _ in 0..TICK_RATE {
+            game.step(TICK_DT);
+        }
+        assert!(game.players["pl"].effects.is_empty());
+        // Everything is back by now, under new IDs.
+        assert_eq!(game.pickups.len(), 4);
+        assert!(game.pickups.ids().all(|id| !spawned.contains(&id)));
+    }
+
+    fn match_game(rules: MatchRules, players: &[&str]) -> GameState {
+        let mut game = GameState::new();
+        game.respawn = RespawnRules::from_secs(0.0, 0.0, TICK_RATE);
+        game.match_state.rules = rules;
+        players.iter().enumerate().for_each(|(i, name)| game.add_player(name, Vec2 { x: 100.0 + 100.0 * i as f32, y: 100.0 }));
+        game
+    }
+
+    /// Has `shooter` kill `target` with a bullet placed right on it.
+    fn frag(game: &mut GameState, shooter: &str, target: &str) -> Vec<GameEvent> {
+        game.players.get_mut(target).unwrap().health = BULLET_DAMAGE;
+        let position = game.players[target].position.clone();
+        game.add_bullet(position, Vec2 { x: 0.0, y: 0.0 }, shooter);
+        let mut events = game.step(TICK_DT);
+        events.extend(game.step(TICK_DT));
+        events
+    }
+
+    fn phases(events: &[GameEvent]) -> Vec<MatchPhase> {
+        events.iter().filter_map(|event| match event {
+            GameEvent::PhaseChanged(phase) => Some(phase.clone()),
+            _ => None
+        }).collect()
+    }
+
+    fn step_until(game: &mut GameState, done: impl Fn(&MatchPhase) -> bool) -> Vec<GameEvent> {
+        let mut events = Vec::new();
+        while !done(&game.match_state.phase) {
+            assert!(game.tick < 10_000, "stuck in {:?}", game.match_state.phase);
+            events.extend(game.step(TICK_DT));
+        }
+        events
+    }
+
+    #[test]
+    fn frag_limit_rounds_make_a_match() {
+        let rules = MatchRules { min_players: 3, countdown_ticks: 3, frag_limit: Some(1), time_limit_ticks: None, rounds: 2, round_over_ticks: 3, ..MatchRules::default() };
+        let mut game = match_game(rules, &["a", "b"]);
+        // Warmup kills don't count.
+        frag(&mut game, "a", "b");
+        assert_eq!(game.scores["a"].kills, 1);
+        assert_eq!(game.match_state.phase, MatchPhase::WaitingForPlayers);
+        game.add_player("c", Vec2 { x: 300.0, y: 100.0 });
+
+        let events = step_until(&mut game, |phase| matches!(phase, MatchPhase::InProgress { .. }));
+        assert!(matches!(phases(&events)[..], [MatchPhase::Countdown { .. }, MatchPhase::InProgress { round: 1, ends_at: None }]));
+        assert_eq!(game.scores["a"], Score::default());
+        assert!(game.players.values().all(|player| player.alive));
+
+        let events = frag(&mut game, "a", "b");
+        assert!(matches!(&phases(&events)[..], [MatchPhase::RoundOver { round: 1, winner: Some(winner), .. }] if winner == "a"));
+        step_until(&mut game, |phase| matches!(phase, MatchPhase::InProgress { round: 2, .. }));
+
+        let events = frag(&mut game, "a", "b");
+        assert_eq!(phases(&events), [MatchPhase::MatchOver]);
+        let results = events.iter().find_map(|event| match event {
+            GameEvent::MatchResults(results) => Some(results.clone()),
+            _ => None
+        }).unwrap();
+        assert_eq!(results.winner.as_deref(), Some("a"));
+        assert_eq!(results.standings.iter().map(|standing| (standing.name.as_str(), standing.rounds_won, standing.score.kills)).collect::<Vec<_>>(),
+            [("a", 2, 2), ("c", 0, 0), ("b", 0, 0)]);
+    }
+
+    #[test]
+    fn time_limit_goes_to_most_kills_or_a_draw() {
+        let rules = MatchRules { countdown_ticks: 1, frag_limit: None, time_limit_ticks: Some(30), rounds: 2, round_over_ticks: 1, ..MatchRules::default() };
+        let mut game = match_game(rules, &["a", "b"]);
+        step_until(&mut game, |phase| matches!(phase, MatchPhase::InProgress { .. }));
+        let events = step_until(&mut game, |phase| matches!(phase, MatchPhase::RoundOver { .. }));
+        assert!(matches!(phases(&events)[..], [MatchPhase::RoundOver { round: 1, winner: None, .. }]));
+
+        step_until(&mut game, |phase| matches!(phase, MatchPhase::InProgress { round: 2, .. }));
+        frag(&mut game, "b", "a");
+        let events = step_until(&mut game, |phase| *phase == MatchPhase::MatchOver);
+        assert!(events.iter().any(|event| matches!(event, GameEvent::MatchResults(results) if results.winner.as_deref() == Some("b"))));
+    }
+
+    #[test]
+    fn last_player_standing_has_no_respawns() {
+        let rules = MatchRules { countdown_ticks: 1, frag_limit: None, time_limit_ticks: None, last_player_standing: true, ..MatchRules::default() };
+        let mut game = match_game(rules, &["a", "b", "c"]);
+        game.respawn = RespawnRules::from_secs(0.1, 0.0, TICK_RATE);
+        step_until(&mut game, |phase| matches!(phase, MatchPhase::InProgress { .. }));
+
+        frag(&mut game, "a", "b");
+        (0..TICK_RATE).for_each(|_| { game.step(TICK_DT); });
+        assert!(!game.players["b"].alive);
+        assert!(matches!(game.match_state.phase, MatchPhase::InProgress { .. }));
+
+        let events = frag(&mut game, "c", "a");
+        assert!(events.iter().any(|event| matches!(event, GameEvent::MatchResults(results) if results.winner.as_deref() == Some("c"))));
+    }
+}
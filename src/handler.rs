@@ -1,14 +1,31 @@
-use std::collections::HashMap;
-use uuid::Uuid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
-use crate::{Result, Lobby, Lobbies, Player, ws::{self}, game_state::{self, GameState, GameEvent, PlayerState, Vec2}, time_util, SetupMessage, Channels};
-use warp::{http::StatusCode, reply::json, ws::Message, Reply};
-use tokio::sync::{mpsc, broadcast};
+use crate::{Result, Lobby, Lobbies, Sessions, GameMode, time_util, config::{ServerConfig, MAX_DURATION}, error::Error, ws::{self}, game_state::{self, GameState, GameEvent, PlayerInput, RespawnRules, Score, Vec2}, SetupMessage, Channels};
+use warp::{http::StatusCode, reply::json, Reply};
+use tokio::sync::{mpsc, broadcast, oneshot, watch};
+use uuid::Uuid;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+
+const REAP_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a stopping lobby waits for its connections to forward the last
+/// event and close their sockets.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Deserialize, Serialize)]
 pub struct CreateLobbyRequest {
     pub name: String,
-    pub player_name: String
+    pub player_name: String,
+    /// How long a disconnected player is kept around for a reconnect,
+    /// the server's `lobby.reconnect_grace_secs` if not given.
+    #[serde(default)]
+    pub reconnect_grace_secs: Option<f32>,
+    /// The server's `lobby.max_players` if not given.
+    #[serde(default)]
+    pub max_players: Option<usize>,
+    /// If set, `/register` only hands out tokens to players who know it.
+    #[serde(default)]
+    pub password: Option<String>
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -16,130 +33,389 @@ pub struct WsBody {
     lobby_name: String
 }
 
+/// Query parameters of the WebSocket upgrade: the session `token` handed out
+/// by `/create_lobby` or `/register`, and optionally `codec=binary` to prefer
+/// a codec when the client's `Hello` offers several.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct WsQuery {
+    pub token: Option<String>,
+    pub codec: Option<String>
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct  LobbyResponse {
     pub url: String,
+    /// Has to be passed as `?token=` when connecting to `url`, and again to
+    /// reconnect after the socket dropped.
+    pub token: String,
     pub game_state: Option<GameState>
 }
 
+/// What `GET /lobbies` and `GET /lobbies/{name}` report about a lobby.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LobbyInfo {
+    pub name: String,
+    pub player_count: usize,
+    pub players: Vec<String>,
+    pub max_players: usize,
+    pub password_protected: bool,
+    /// Unix time in seconds.
+    pub created_at: f64,
+    pub uptime_secs: f64,
+    pub game_mode: GameMode
+}
+
+/// One row of `GET /lobbies/{name}/scoreboard`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScoreboardEntry {
+    pub name: String,
+    pub kills: u32,
+    pub deaths: u32,
+    pub damage_dealt: i32,
+    pub accuracy: f32
+}
+
+impl ScoreboardEntry {
+    pub fn new(name: &str, score: &Score) -> Self {
+        ScoreboardEntry {
+            name: name.to_string(),
+            kills: score.kills,
+            deaths: score.deaths,
+            damage_dealt: score.damage_dealt,
+            accuracy: score.accuracy()
+        }
+    }
+}
+
+/// Most kills first, then fewest deaths, then by name.
+pub fn scoreboard<'a>(scores: impl IntoIterator<Item = (&'a String, &'a Score)>) -> Vec<ScoreboardEntry> {
+    let mut entries: Vec<ScoreboardEntry> = scores.into_iter().map(|(name, score)| ScoreboardEntry::new(name, score)).collect();
+    entries.sort_by(|a, b| b.kills.cmp(&a.kills).then(a.deaths.cmp(&b.deaths)).then_with(|| a.name.cmp(&b.name)));
+    entries
+}
+
 #[derive( Serialize, Deserialize, Debug)]
 pub struct EnterLobby {
     pub name: String,
-    pub player_name: String
+    pub player_name: String,
+    #[serde(default)]
+    pub password: Option<String>
 }
 
 
-pub async fn create_lobby(req: CreateLobbyRequest, lobbies: Lobbies) -> Result<impl Reply> {
-    //let uuid =  Uuid::new_v4().as_simple().to_string();
+fn new_session_token() -> String {
+    Uuid::new_v4().as_simple().to_string()
+}
+
+/// Starts a lobby with its creator already registered. Fails with a conflict
+/// if the name is taken, rather than replacing the running game.
+pub async fn create_lobby(req: CreateLobbyRequest, lobbies: Lobbies, config: Arc<ServerConfig>) -> Result<impl Reply> {
     println!("received: {:?}", req.name);
+    let max_players = req.max_players.unwrap_or(config.lobby.max_players);
+    if max_players == 0 {
+        return Err(Error::BadRequest("max_players must be at least 1".to_string()).into());
+    }
+    let mut locked = lobbies.write().await;
+    if locked.contains_key(&req.name) {
+        return Err(Error::LobbyExists(req.name).into());
+    }
     let lobby_name = req.name.clone();
-    let player_name = req.player_name.clone();
-    let mut game_state = GameState {
-        players: HashMap::from_iter([(req.player_name.clone(),
-            PlayerState {
-                position: game_state::Vec2 { x: 100.0, y: 100.0 },
-                velocity: game_state::Vec2 { x: 0.0, y: 0.0 },
-                angle: 0.0,
-                name: req.player_name.clone(),
-                health: 100,
-                alive: true
-            }),
-        ]),
-        bullets: Vec::with_capacity(50),
-        last_time: time_util::get_current_time(),
-        actions: Vec::with_capacity(10)
+    let mut game_state = GameState::new();
+    game_state.respawn = RespawnRules::from_secs(config.lobby.respawn_delay_secs, config.lobby.spawn_protection_secs, config.tick_rate);
+    game_state.match_state.rules = config.lobby.match_rules.rules(config.tick_rate);
+    game_state.map = config.arena.clone();
+    game_state.weapons = Arc::new(config.weapons.clone());
+    let spawn = game_state.spawn_point_for(&req.player_name);
+    game_state.add_player(&req.player_name, spawn);
+    let (setup_tx, setup_rx) = mpsc::unbounded_channel();
+    let token = new_session_token();
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::from_iter([(req.player_name.clone(), token.clone())])));
+    let (players_tx, players_rx) = watch::channel(vec![req.player_name.clone()]);
+    let (scores_tx, scores_rx) = watch::channel(game_state.scores.clone());
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
+    let initial_state = game_state.clone();
+    let reconnect_grace = match req.reconnect_grace_secs {
+        Some(secs) => Duration::try_from_secs_f32(secs).ok()
+            .filter(|grace| *grace <= MAX_DURATION)
+            .ok_or_else(|| Error::BadRequest(format!("reconnect_grace_secs must be between 0 and {} seconds", MAX_DURATION.as_secs())))?,
+        None => config.lobby.reconnect_grace
+    };
+    let setup = LobbySetup {
+        setup_rx,
+        shutdown: shutdown_rx,
+        sessions: sessions.clone(),
+        players_tx,
+        scores_tx,
+        tick_rate: config.tick_rate,
+        broadcast_capacity: config.broadcast_capacity,
+        reconnect_grace,
+        creator: req.player_name.clone()
     };
-    let (setup_tx, mut setup_rx) = mpsc::unbounded_channel();
-    let (ch_tx, mut ch_rx) = mpsc::unbounded_channel();
-
-    lobbies.write().await.insert(lobby_name.clone(), Lobby { 
-        game_setup_sender: setup_tx, 
-        game_ch_receiver: ch_rx });
 
-    let initial_state = game_state.clone();
+    let task = tokio::task::spawn(run_lobby(game_state, setup));
 
-    tokio::task::spawn(async move  {
-        
-        let setup = setup_rx.recv().await.unwrap();
-        let (br_tx, mut event_rx, event_tx) = match setup {
-            SetupMessage::AddPlayer(name) => {
-                let (br_tx, mut br_rx) = broadcast::channel(20);
-                let (event_tx, mut event_rx): (mpsc::UnboundedSender<GameEvent>, mpsc::UnboundedReceiver<GameEvent>) = mpsc::unbounded_channel();
-                // ch_tx.send(Channels {
-                //     broadcast_receiver: Some(br_tx.subscribe()),
-                //     event_sender: Some(event_tx.clone())
-                // }).unwrap();
-                (Some(br_tx), Some(event_rx), Some(event_tx))
-            },
-            _ => {(None, None, None)}
-        };
-
-        println!("started game loop");
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_millis(35)).await;
-            let events = game_state.update(time_util::get_current_time());
-            
-            events.iter().for_each(|event| {
-                br_tx.as_ref().unwrap().send(event.clone()).unwrap();
-            });
-            if let Ok(event) = event_rx.as_mut().unwrap().try_recv() {
-                println!("received event: {:?}", event);
-                br_tx.as_ref().unwrap().send(event.clone()).unwrap();
-                game_state.react_to_event(event);
-            }
-            if let Ok(setup_msg) = setup_rx.try_recv() {
-                match setup_msg {
-                    SetupMessage::AddPlayer(name) => {
-                        game_state.add_player(&name, Vec2 {x: 0.0, y: 0.0});
-                        println!("added new player: {:?}", name);
-                        br_tx.as_ref().unwrap().send(GameEvent::AddPlayer { x: 0.0, y: 0.0, name: name }).unwrap();
-                        br_tx.as_ref().unwrap().send(GameEvent::GameStateSync(game_state.clone())).unwrap();
-                    },
-                    SetupMessage::GetChannels => {
-                        ch_tx.send(Channels {
-                            broadcast_receiver: Some(br_tx.as_ref().unwrap().subscribe()),
-                            event_sender: Some(event_tx.as_ref().unwrap().clone())
-                        }).unwrap();
-                        br_tx.as_ref().unwrap().send(GameEvent::GameStateSync(game_state.clone())).unwrap();
-                    }
-                }
-                
-            }
-        };
-    });
+    locked.insert(lobby_name.clone(), Lobby { 
+        game_setup_sender: setup_tx,
+        sessions,
+        players: players_rx,
+        scores: scores_rx,
+        max_players,
+        password: req.password,
+        game_mode: GameMode::default(),
+        created_at: time_util::get_current_time(),
+        started: Instant::now(),
+        empty_since: None,
+        shutdown: shutdown_tx,
+        task });
 
     let msg = LobbyResponse {
-        url: format!("ws://localhost:8000/ws/{}/{}", lobby_name, req.player_name),
+        url: format!("{}/ws/{}/{}", config.public_url(), lobby_name, req.player_name),
+        token,
         game_state: Some(initial_state)
     };
     println!("sent : {:?}", msg);
     Ok(json(&msg))
 }
 
+/// What a lobby's game loop is started with, besides its `GameState`.
+struct LobbySetup {
+    setup_rx: mpsc::UnboundedReceiver<SetupMessage>,
+    shutdown: oneshot::Receiver<GameEvent>,
+    sessions: Sessions,
+    players_tx: watch::Sender<Vec<String>>,
+    scores_tx: watch::Sender<BTreeMap<String, Score>>,
+    tick_rate: u32,
+    broadcast_capacity: usize,
+    reconnect_grace: Duration,
+    creator: String
+}
+
+/// The lobby's game loop. It owns the `GameState`, applies player inputs and
+/// setup messages once per tick and broadcasts the resulting events.
+///
+/// Players whose socket closed are kept for `reconnect_grace` so that they
+/// can reconnect into the same `PlayerState`; after that they are removed and
+/// a `PlayerLeft` event goes out and their session token is revoked. The
+/// creator starts out in that state too, until their own socket connects.
+///
+/// The loop runs until `shutdown` fires or its sender is dropped. It then
+/// broadcasts the event sent through `shutdown`, or `LobbyClosed` if the
+/// sender was dropped, and waits up to `DRAIN_TIMEOUT` for the connections
+/// to pass it on and go away.
+async fn run_lobby(mut game_state: GameState, setup: LobbySetup) {
+    let LobbySetup { mut setup_rx, mut shutdown, sessions, players_tx, scores_tx, tick_rate, broadcast_capacity, reconnect_grace, creator } = setup;
+    let tick_dt = 1.0 / tick_rate as f32;
+    let (br_tx, _) = broadcast::channel(broadcast_capacity);
+    let (event_tx, mut event_rx): (mpsc::UnboundedSender<PlayerInput>, mpsc::UnboundedReceiver<PlayerInput>) = mpsc::unbounded_channel();
+    let mut disconnected: HashMap<String, Instant> = HashMap::from_iter([(creator, Instant::now())]);
+    // The newest connection of every player.
+    let mut connections: HashMap<String, u64> = HashMap::new();
+    let mut interval = time::interval(Duration::from_secs_f32(tick_dt));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    println!("started game loop");
+    let last_event = loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            event = &mut shutdown => break event.unwrap_or(GameEvent::LobbyClosed)
+        }
+        while let Ok(input) = event_rx.try_recv() {
+            println!("received input: {:?}", input);
+            // Inputs that changed nothing, like shots the weapon isn't ready
+            // for, are dropped here so that spamming them doesn't flood
+            // everyone else's connection.
+            let event = input.event.clone();
+            if game_state.apply_input(input) {
+                let _ = br_tx.send(event);
+            }
+        }
+        while let Ok(setup_msg) = setup_rx.try_recv() {
+            match setup_msg {
+                SetupMessage::AddPlayer(name, connection) => {
+                    disconnected.remove(&name);
+                    connections.insert(name.clone(), connection);
+                    if game_state.players.contains_key(&name) {
+                        println!("player reconnected: {:?}", name);
+                        continue;
+                    }
+                    let spawn = game_state.spawn_point_for(&name);
+                    game_state.add_player(&name, spawn.clone());
+                    println!("added new player: {:?}", name);
+                    let _ = br_tx.send(GameEvent::AddPlayer { x: spawn.x, y: spawn.y, name });
+                    let _ = br_tx.send(GameEvent::GameStateSync(game_state.clone()));
+                },
+                SetupMessage::GetChannels(reply) => {
+                    let _ = reply.send(Channels {
+                        broadcast_receiver: br_tx.subscribe(),
+                        event_sender: event_tx.clone()
+                    });
+                    let _ = br_tx.send(GameEvent::GameStateSync(game_state.clone()));
+                },
+                SetupMessage::Disconnected(name, connection) => {
+                    if connections.get(&name) != Some(&connection) {
+                        // Replaced by a newer connection under the same session.
+                        continue;
+                    }
+                    println!("player disconnected: {:?}", name);
+                    if let Some(player) = game_state.players.get_mut(&name) {
+                        player.velocity = Vec2 { x: 0.0, y: 0.0 };
+                    }
+                    disconnected.insert(name, Instant::now());
+                }
+            }
+        }
+        let now = Instant::now();
+        let expired: Vec<String> = disconnected.iter()
+            .filter(|(_, since)| now.duration_since(**since) >= reconnect_grace)
+            .map(|(name, _)| name.clone())
+            .collect();
+        expired.into_iter().for_each(|name| {
+            disconnected.remove(&name);
+            connections.remove(&name);
+            sessions.lock().unwrap().remove(&name);
+            println!("removing player: {:?}", name);
+            game_state.remove_player(&name);
+            let _ = br_tx.send(GameEvent::PlayerLeft(name));
+        });
+        game_state.step(tick_dt).into_iter().for_each(|event| {
+            if let GameEvent::MatchResults(results) = &event {
+                println!("match over, winner: {:?}", results.winner);
+            }
+            let _ = br_tx.send(event);
+        });
+        players_tx.send_if_modified(|names| {
+            if names.iter().eq(game_state.players.keys()) {
+                return false;
+            }
+            *names = game_state.players.keys().cloned().collect();
+            true
+        });
+        scores_tx.send_if_modified(|scores| {
+            if *scores == game_state.scores {
+                return false;
+            }
+            scores.clone_from(&game_state.scores);
+            true
+        });
+        if game_state.tick.is_multiple_of(game_state::SNAPSHOT_INTERVAL_TICKS) {
+            let _ = br_tx.send(GameEvent::GameStateSync(game_state.clone()));
+        }
+    };
+    println!("stopped game loop");
+    // Connections forward this, see the channel close and close their
+    // sockets; each one holds an `event_tx` clone until it is done.
+    let _ = br_tx.send(last_event);
+    drop(br_tx);
+    drop(event_tx);
+    let _ = time::timeout(DRAIN_TIMEOUT, async {
+        while event_rx.recv().await.is_some() {}
+    }).await;
+}
+
+pub async fn list_lobbies(lobbies: Lobbies) -> Result<impl Reply> {
+    let locked = lobbies.read().await;
+    let mut infos: Vec<LobbyInfo> = locked.iter().map(|(name, lobby)| lobby.info(name)).collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(json(&infos))
+}
+
+pub async fn get_lobby(name: String, lobbies: Lobbies) -> Result<impl Reply> {
+    let locked = lobbies.read().await;
+    let lobby = locked.get(&name).ok_or_else(|| Error::LobbyNotFound(name.clone()))?;
+    Ok(json(&lobby.info(&name)))
+}
+
+pub async fn get_scoreboard(name: String, lobbies: Lobbies) -> Result<impl Reply> {
+    let locked = lobbies.read().await;
+    let lobby = locked.get(&name).ok_or_else(|| Error::LobbyNotFound(name.clone()))?;
+    let entries = scoreboard(lobby.scores.borrow().iter());
+    Ok(json(&entries))
+}
+
 pub async fn delete_lobby(name: String, lobbies: Lobbies) -> Result<impl Reply> {
-    lobbies.write().await.remove(&name);
+    let lobby = lobbies.write().await.remove(&name).ok_or(Error::LobbyNotFound(name))?;
+    lobby.close().await;
     Ok(StatusCode::OK)
 }
 
-pub async fn enter_lobby(req: EnterLobby, lobbies: Lobbies) ->  Result<impl Reply> {
-    let uuid =  Uuid::new_v4().as_simple().to_string();
-    let locked = lobbies.read().await;
-    locked.get(&req.name).unwrap();
+/// Closes lobbies that have had no players for `idle_timeout`, counting
+/// from the last registration if that was later. Runs forever, so it is
+/// meant to be spawned next to the server.
+pub async fn reap_idle_lobbies(lobbies: Lobbies, idle_timeout: Duration) {
+    let mut interval = time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut idle = Vec::new();
+        {
+            let mut locked = lobbies.write().await;
+            locked.iter_mut().for_each(|(name, lobby)| {
+                if !lobby.players.borrow().is_empty() {
+                    lobby.empty_since = None;
+                } else if now.duration_since(*lobby.empty_since.get_or_insert(now)) >= idle_timeout {
+                    idle.push(name.clone());
+                }
+            });
+            idle.iter().for_each(|name| {
+                if let Some(lobby) = locked.remove(name) {
+                    println!("closing idle lobby: {:?}", name);
+                    // Awaiting the loop here would hold the write lock.
+                    tokio::spawn(lobby.close());
+                }
+            });
+        }
+    }
+}
+
+/// Hands out a session token for `player_name`, unless the password is
+/// wrong, the lobby is full or someone in it already holds one under that
+/// name.
+pub async fn enter_lobby(req: EnterLobby, lobbies: Lobbies, config: Arc<ServerConfig>) ->  Result<impl Reply> {
+    let mut locked = lobbies.write().await;
+    let lobby = locked.get_mut(&req.name).ok_or_else(|| Error::LobbyNotFound(req.name.clone()))?;
+    if !lobby.check_password(req.password.as_deref()) {
+        return Err(Error::WrongPassword.into());
+    }
+    let token = new_session_token();
+    {
+        let mut sessions = lobby.sessions.lock().unwrap();
+        if sessions.contains_key(&req.player_name) {
+            return Err(Error::NameTaken(req.player_name).into());
+        }
+        if sessions.len() >= lobby.max_players {
+            return Err(Error::LobbyFull(req.name).into());
+        }
+        sessions.insert(req.player_name.clone(), token.clone());
+    }
+    // Give the player the whole idle timeout to connect.
+    lobby.empty_since = None;
 
     Ok(json(&LobbyResponse {
-        url: format!("ws://localhost:8000/ws/{}/{}", req.name, req.player_name),
+        url: format!("{}/ws/{}/{}", config.public_url(), req.name, req.player_name),
+        token,
         game_state: None
     }))
 
 }
 
-pub async fn ws_handler(ws: warp::ws::Ws, lobby_name: String, id: String, lobbies: Lobbies) ->  Result<impl Reply> {
+/// Upgrades to a WebSocket if `query.token` is the session token of player
+/// `id`. Tokens are only handed out after the password, capacity and name
+/// checks, so holding one covers those. Reconnecting with the same token
+/// within the lobby's grace period picks up the player where they left off.
+pub async fn ws_handler(ws: warp::ws::Ws, lobby_name: String, id: String, query: WsQuery, lobbies: Lobbies, config: Arc<ServerConfig>) ->  Result<impl Reply> {
     println!("tryng to ws connect to: {:?}", lobby_name);
-    if lobbies.read().await.contains_key(&lobby_name) {
-        Ok(ws.on_upgrade(move |socket| ws::player_connection(socket, lobbies, lobby_name, id)))
-    } else {
-        Err(warp::reject::not_found())
+    {
+        let locked = lobbies.read().await;
+        let lobby = locked.get(&lobby_name).ok_or_else(|| Error::LobbyNotFound(lobby_name.clone()))?;
+        if !query.token.as_deref().is_some_and(|token| lobby.authorize(&id, token)) {
+            return Err(Error::Unauthorized.into());
+        }
+        let players = lobby.players.borrow();
+        if players.len() >= lobby.max_players && !players.contains(&id) {
+            return Err(Error::LobbyFull(lobby_name).into());
+        }
     }
+    Ok(ws.on_upgrade(move |socket| ws::player_connection(socket, lobbies, lobby_name, id, query.codec, config.tick_rate)))
 }
 
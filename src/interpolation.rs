@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use crate::game_state::{GameState, Vec2};
+
+pub const DEFAULT_DELAY: f64 = 0.1;
+pub const DEFAULT_MAX_EXTRAPOLATION: f64 = 0.25;
+const CAPACITY: usize = 32;
+
+pub struct Snapshot {
+    pub time: f64,
+    pub state: GameState
+}
+
+/// Keeps the last few server snapshots and renders remote entities `delay`
+/// seconds in the past, blending between the two snapshots around that time.
+/// When no newer snapshot has arrived yet, positions are extrapolated from
+/// the latest one for at most `max_extrapolation` seconds.
+pub struct InterpolationBuffer {
+    snapshots: VecDeque<Snapshot>,
+    delay: f64,
+    max_extrapolation: f64
+}
+
+impl InterpolationBuffer {
+    pub fn new(delay: f64) -> Self {
+        InterpolationBuffer {
+            snapshots: VecDeque::with_capacity(CAPACITY),
+            delay,
+            max_extrapolation: DEFAULT_MAX_EXTRAPOLATION
+        }
+    }
+
+    pub fn with_max_extrapolation(mut self, max_extrapolation: f64) -> Self {
+        self.max_extrapolation = max_extrapolation;
+        self
+    }
+
+    pub fn delay(&self) -> f64 {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, delay: f64) {
+        self.delay = delay;
+    }
+
+    /// Stores a snapshot received at `time`. Snapshots older than the newest
+    /// one already stored arrived out of order and are dropped.
+    pub fn push(&mut self, time: f64, state: GameState) {
+        if self.snapshots.back().is_some_and(|last| last.time >= time) {
+            return;
+        }
+        if self.snapshots.len() == CAPACITY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot { time, state });
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Returns the state to render at `now`, or `None` before the first
+    /// snapshot has arrived.
+    pub fn sample(&self, now: f64) -> Option<GameState> {
+        let render_time = now - self.delay;
+        let newer = self.snapshots.iter().position(|snapshot| snapshot.time > render_time);
+        match newer {
+            Some(0) => self.snapshots.front().map(|snapshot| snapshot.state.clone()),
+            Some(idx) => {
+                let from = &self.snapshots[idx - 1];
+                let to = &self.snapshots[idx];
+                let alpha = ((render_time - from.time) / (to.time - from.time)) as f32;
+                Some(Self::blend(from, to, alpha, render_time))
+            },
+            None => {
+                let latest = self.snapshots.back()?;
+                let ahead = (render_time - latest.time).min(self.max_extrapolation) as f32;
+                let mut state = latest.state.clone();
+                state.players.values_mut().for_each(|player| {
+                    player.position = player.position.sum(&player.velocity.scale(ahead));
+                });
+                state.bullets.values_mut().for_each(|bullet| bullet.position = bullet.position_after(ahead));
+                Some(state)
+            }
+        }
+    }
+
+    fn blend(from: &Snapshot, to: &Snapshot, alpha: f32, render_time: f64) -> GameState {
+        let mut state = from.state.clone();
+        state.players.iter_mut().for_each(|(name, player)| {
+            if let Some(next) = to.state.players.get(name) {
+                player.position = lerp(&player.position, &next.position, alpha);
+                player.angle = lerp_angle(player.angle, next.angle, alpha);
+            }
+        });
+        // Bullets fly in straight lines, so advancing them from the older
+        // snapshot is exact and doesn't need to match them up between snapshots.
+        let elapsed = (render_time - from.time) as f32;
+        state.bullets.values_mut().for_each(|bullet| bullet.position = bullet.position_after(elapsed));
+        state
+    }
+}
+
+fn lerp(from: &Vec2, to: &Vec2, alpha: f32) -> Vec2 {
+    Vec2 { x: from.x + (to.x - from.x) * alpha, y: from.y + (to.y - from.y) * alpha }
+}
+
+/// Interpolates angles in degrees along the shortest arc.
+fn lerp_angle(from: f32, to: f32, alpha: f32) -> f32 {
+    let diff = (to - from + 180.0).rem_euclid(360.0) - 180.0;
+    (from + diff * alpha).rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn snapshot(x: f32, vx: f32, angle: f32) -> GameState {
+        let mut game = GameState::new();
+        game.add_player("remote", Vec2 { x, y: 0.0 });
+        let player = game.players.get_mut("remote").unwrap();
+        player.velocity = Vec2 { x: vx, y: 0.0 };
+        player.angle = angle;
+        game
+    }
+
+    fn remote_x(state: &GameState) -> f32 {
+        state.players["remote"].position.x
+    }
+
+    #[test]
+    fn blends_between_snapshots() {
+        let mut buffer = InterpolationBuffer::new(0.1);
+        assert!(buffer.sample(0.0).is_none());
+        buffer.push(0.0, snapshot(0.0, 10.0, 350.0));
+        buffer.push(0.1, snapshot(10.0, 10.0, 10.0));
+        buffer.push(0.2, snapshot(20.0, 10.0, 10.0));
+
+        let state = buffer.sample(0.15).unwrap();
+        assert!((remote_x(&state) - 5.0).abs() < 1e-4);
+        assert!((state.players["remote"].angle - 0.0).abs() < 1e-3 || (state.players["remote"].angle - 360.0).abs() < 1e-3);
+
+        let state = buffer.sample(0.275).unwrap();
+        assert!((remote_x(&state) - 17.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn extrapolates_when_packets_are_late() {
+        let mut buffer = InterpolationBuffer::new(0.1).with_max_extrapolation(0.2);
+        buffer.push(0.0, snapshot(0.0, 10.0, 0.0));
+        buffer.push(0.1, snapshot(1.0, 10.0, 0.0));
+
+        let state = buffer.sample(0.25).unwrap();
+        assert!((remote_x(&state) - 1.5).abs() < 1e-4);
+
+        // Extrapolation stops after max_extrapolation seconds.
+        let state = buffer.sample(5.0).unwrap();
+        assert!((remote_x(&state) - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn delay_is_configurable_and_stale_snapshots_are_dropped() {
+        let mut buffer = InterpolationBuffer::new(0.0);
+        buffer.push(1.0, snapshot(0.0, 0.0, 0.0));
+        buffer.push(2.0, snapshot(10.0, 0.0, 0.0));
+        buffer.push(1.5, snapshot(100.0, 0.0, 0.0));
+        assert!((remote_x(&buffer.sample(1.5).unwrap()) - 5.0).abs() < 1e-4);
+
+        buffer.set_delay(0.5);
+        assert!((remote_x(&buffer.sample(1.5).unwrap()) - 0.0).abs() < 1e-4);
+        assert!((remote_x(&buffer.sample(2.0).unwrap()) - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bullets_are_advanced_along_their_path() {
+        let mut from = GameState::new();
+        let bullet = from.add_bullet(Vec2 { x: 0.0, y: 0.0 }, Vec2 { x: 20.0, y: 0.0 }, "remote");
+        let mut to = from.clone();
+        to.step(0.1);
+
+        let mut buffer = InterpolationBuffer::new(0.1);
+        buffer.push(0.0, from);
+        buffer.push(0.1, to);
+        let state = buffer.sample(0.15).unwrap();
+        assert!((state.bullets.get(bullet).unwrap().position.x - 1.0).abs() < 1e-4);
+    }
+}
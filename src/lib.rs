@@ -2,18 +2,36 @@ pub mod handler;
 pub mod ws;
 pub mod game_state;
 pub mod time_util;
+pub mod prediction;
+pub mod interpolation;
+pub mod snapshot;
+pub mod codec;
+pub mod protocol;
+pub mod error;
+pub mod config;
+pub mod map;
+pub mod spatial;
+pub mod entity;
+pub mod weapon;
+pub mod pickup;
 
-use game_state::Action;
-use warp::{ws::Message, Filter, Rejection};
-use game_state::GameEvent;
-use std::{convert::Infallible, collections::HashMap};
-use std::sync::Arc;
-use tokio::sync::{mpsc, broadcast, RwLock};
+use warp::{ws::Message, Filter, Rejection, Reply};
+use game_state::{GameEvent, PlayerInput, Score};
+use config::ServerConfig;
+use std::{convert::Infallible, collections::{BTreeMap, HashMap}, future::Future, net::SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, broadcast, oneshot, watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
 
 
 
 pub type Result<T> = std::result::Result<T, Rejection>;
 pub type Lobbies = Arc<RwLock<HashMap<String, Lobby>>>;
+/// Session tokens of a lobby's players, by player name. Shared with the lobby
+/// loop so that a player's token is revoked when they are removed.
+pub type Sessions = Arc<Mutex<HashMap<String, String>>>;
 
 #[derive(Debug, Clone)]
 pub struct Player{
@@ -22,28 +40,160 @@ pub struct Player{
 
 }
 
+/// Messages from the connection tasks to the lobby loop. The `u64` is the id
+/// of the connection, so that a socket replaced by a reconnect can't mark the
+/// player as disconnected when it finally closes.
 pub enum SetupMessage {
-    AddPlayer(String),
-    GetChannels
+    AddPlayer(String, u64),
+    GetChannels(oneshot::Sender<Channels>),
+    /// The player's socket closed; they are removed once the lobby's
+    /// reconnect grace period runs out.
+    Disconnected(String, u64)
 }
 
 pub struct Channels {
-    broadcast_receiver: Option<broadcast::Receiver<GameEvent>>,
-    event_sender: Option<mpsc::UnboundedSender<GameEvent>>
+    broadcast_receiver: broadcast::Receiver<GameEvent>,
+    event_sender: mpsc::UnboundedSender<PlayerInput>
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum GameMode {
+    #[default]
+    FreeForAll
+}
+
 pub struct Lobby {
     //pub players: HashMap<String, Player>,
     pub game_setup_sender: mpsc::UnboundedSender<SetupMessage>,
-    pub game_ch_receiver: mpsc::UnboundedReceiver<Channels>
+    pub sessions: Sessions,
+    /// Names of the players in the game, kept up to date by the lobby loop.
+    pub players: watch::Receiver<Vec<String>>,
+    /// Scores of everyone who played in the lobby, likewise kept up to date.
+    pub scores: watch::Receiver<BTreeMap<String, Score>>,
+    pub max_players: usize,
+    pub password: Option<String>,
+    pub game_mode: GameMode,
+    /// Unix time in seconds.
+    pub created_at: f64,
+    pub started: Instant,
+    /// When the lobby was last seen without players, for the idle reaper.
+    /// Cleared when someone registers.
+    pub empty_since: Option<Instant>,
+    /// Tells the game loop to stop and which event to send its players last;
+    /// dropping it stops the loop with `LobbyClosed`.
+    pub shutdown: oneshot::Sender<GameEvent>,
+    pub task: JoinHandle<()>
+}
+
+impl Lobby {
+    /// Stops the game loop and waits for it to finish. Connected players get
+    /// a `LobbyClosed` event, after which their sockets are closed.
+    pub async fn close(self) {
+        self.close_with(GameEvent::LobbyClosed).await;
+    }
+
+    /// Like `close`, with `last_event` sent to the players instead.
+    pub async fn close_with(self, last_event: GameEvent) {
+        let _ = self.shutdown.send(last_event);
+        let _ = self.task.await;
+    }
+
+    pub fn info(&self, name: &str) -> handler::LobbyInfo {
+        let players = self.players.borrow().clone();
+        handler::LobbyInfo {
+            name: name.to_string(),
+            player_count: players.len(),
+            players,
+            max_players: self.max_players,
+            password_protected: self.password.is_some(),
+            created_at: self.created_at,
+            uptime_secs: self.started.elapsed().as_secs_f64(),
+            game_mode: self.game_mode
+        }
+    }
+
+    /// Whether `token` is the session token handed out to `player_name`.
+    pub fn authorize(&self, player_name: &str, token: &str) -> bool {
+        self.sessions.lock().unwrap().get(player_name).is_some_and(|session| session == token)
+    }
+
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        self.password.as_deref().is_none_or(|expected| password == Some(expected))
+    }
 }
 
-pub async fn server() {
+pub async fn server(config: ServerConfig) {
+    match server_with_shutdown(config, shutdown_signal()) {
+        Ok((_, serve)) => serve.await,
+        Err(e) => eprintln!("could not start server: {}", e)
+    }
+}
+
+/// Binds the server and returns the address it listens on together with the
+/// future that runs it. Once `shutdown` resolves, no new connections are
+/// accepted, every lobby is sent a `ServerShutdown` event carrying the reason
+/// `shutdown` resolved with, and the future finishes after all lobby loops
+/// have stopped.
+pub fn server_with_shutdown(
+    config: ServerConfig,
+    shutdown: impl Future<Output = String> + Send + 'static
+) -> std::result::Result<(SocketAddr, impl Future<Output = ()>), warp::Error> {
     let lobbies: Lobbies = Arc::new(RwLock::new(HashMap::new()));
+    let idle_timeout = config.idle_timeout;
+    let bind = config.bind;
+    let public_url = config.public_url();
+    let (reason_tx, reason_rx) = oneshot::channel();
+    let (addr, serving) = warp::serve(routes(lobbies.clone(), Arc::new(config)))
+        .try_bind_with_graceful_shutdown(bind, async move {
+            let _ = reason_tx.send(shutdown.await);
+        })?;
+    println!("starting server on {} as {}", addr, public_url);
+    let run = async move {
+        let reaper = tokio::spawn(handler::reap_idle_lobbies(lobbies.clone(), idle_timeout));
+        serving.await;
+        reaper.abort();
+        let reason = reason_rx.await.unwrap_or_default();
+        println!("shutting down: {}", reason);
+        let mut closing = JoinSet::new();
+        for (_, lobby) in lobbies.write().await.drain() {
+            closing.spawn(lobby.close_with(GameEvent::ServerShutdown { reason: reason.clone() }));
+        }
+        while closing.join_next().await.is_some() {}
+        println!("server stopped");
+    };
+    Ok((addr, run))
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix, with the name of the signal.
+pub async fn shutdown_signal() -> String {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT".to_string(),
+                _ = term.recv() => "SIGTERM".to_string()
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT".to_string()
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT".to_string()
+    }
+}
+
+pub fn routes(lobbies: Lobbies, config: Arc<ServerConfig>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let lobby_creation = warp::path("create_lobby");
     let lobby_routes = lobby_creation
         .and(warp::post())
         .and(warp::body::json())
         .and(with_lobbies(lobbies.clone()))
+        .and(with_config(config.clone()))
         .and_then(handler::create_lobby)
         .or(lobby_creation
                 .and(warp::delete())
@@ -51,28 +201,50 @@ pub async fn server() {
                 .and(with_lobbies(lobbies.clone()))
                 .and_then(handler::delete_lobby));
 
+    let lobby_listing = warp::path("lobbies")
+            .and(warp::get())
+            .and(warp::path::end())
+            .and(with_lobbies(lobbies.clone()))
+            .and_then(handler::list_lobbies)
+            .or(warp::path("lobbies")
+                .and(warp::get())
+                .and(warp::path::param())
+                .and(warp::path::end())
+                .and(with_lobbies(lobbies.clone()))
+                .and_then(handler::get_lobby))
+            .or(warp::path!("lobbies" / String / "scoreboard")
+                .and(warp::get())
+                .and(with_lobbies(lobbies.clone()))
+                .and_then(handler::get_scoreboard));
+
     let enter_lobby = warp::path("register")
             .and(warp::post())
             .and(warp::body::json())
             .and(with_lobbies(lobbies.clone()))
+            .and(with_config(config.clone()))
             .and_then(handler::enter_lobby);
 
     let ws_route = warp::path("ws")
             .and(warp::ws())
             .and(warp::path::param())
             .and(warp::path::param())
+            .and(warp::query::<handler::WsQuery>())
             .and(with_lobbies(lobbies.clone()))
+            .and(with_config(config))
             .and_then(handler::ws_handler);
 
-    let routes = lobby_routes
+    lobby_routes
+            .or(lobby_listing)
             .or(enter_lobby)
             .or(ws_route)
-            .with(warp::cors().allow_any_origin());
-
-    println!("starting server");
-    warp::serve(routes).run(([127, 0, 0, 1], 8000)).await;
+            .recover(error::handle_rejection)
+            .with(warp::cors().allow_any_origin())
 }
 
 fn with_lobbies(lobbies: Lobbies) -> impl Filter<Extract = (Lobbies,), Error = Infallible> + Clone {
     warp::any().map(move || lobbies.clone())
+}
+
+fn with_config(config: Arc<ServerConfig>) -> impl Filter<Extract = (Arc<ServerConfig>,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
 }
\ No newline at end of file
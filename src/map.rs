@@ -0,0 +1,337 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::game_state::{Vec2, PLAYER_RADIUS_SIZE};
+use crate::pickup::{PickupSpawn, PICKUP_RADIUS_SIZE};
+use crate::spatial::sweep_circle;
+
+pub const DEFAULT_WIDTH: f32 = 800.0;
+pub const DEFAULT_HEIGHT: f32 = 600.0;
+/// Spawn points of the default map, spread over the default window.
+pub const DEFAULT_SPAWN_POINTS: [(f32, f32); 8] = [
+    (100.0, 100.0), (700.0, 500.0), (700.0, 100.0), (100.0, 500.0),
+    (400.0, 100.0), (400.0, 500.0), (100.0, 300.0), (700.0, 300.0)
+];
+
+/// A static solid shape in the arena.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Obstacle {
+    /// Axis-aligned, with `(x, y)` its top left corner.
+    Rect { x: f32, y: f32, width: f32, height: f32 },
+    Circle { x: f32, y: f32, radius: f32 }
+}
+
+/// The arena a lobby is played in: a `width` by `height` rectangle starting
+/// at the origin, with obstacles in it. Read from a JSON or RON file, e.g.
+///
+/// ```ron
+/// (
+///     width: 800.0,
+///     height: 600.0,
+///     obstacles: [
+///         Rect(x: 350.0, y: 250.0, width: 100.0, height: 100.0),
+///         Circle(x: 200.0, y: 300.0, radius: 40.0),
+///     ],
+///     spawn_points: [(x: 100.0, y: 100.0), (x: 700.0, y: 500.0)],
+///     pickups: [
+///         (position: (x: 400.0, y: 150.0), kind: Health(amount: 50), respawn_secs: 20.0),
+///     ],
+/// )
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Map {
+    pub width: f32,
+    pub height: f32,
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    pub spawn_points: Vec<Vec2>,
+    #[serde(default)]
+    pub pickups: Vec<PickupSpawn>
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Read(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(String)
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            MapError::Parse(e) => write!(f, "bad map file: {}", e),
+            MapError::Invalid(e) => write!(f, "bad map: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl Default for Map {
+    fn default() -> Self {
+        Map {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            obstacles: Vec::new(),
+            spawn_points: DEFAULT_SPAWN_POINTS.iter().map(|&(x, y)| Vec2 { x, y }).collect(),
+            pickups: Vec::new()
+        }
+    }
+}
+
+impl Obstacle {
+    /// Where a circle at `center` has to go to stop overlapping the
+    /// obstacle, or `None` if it doesn't. It is moved out along the shortest
+    /// way, so that movement into the obstacle turns into sliding along it.
+    pub fn push_out(&self, center: &Vec2, radius: f32) -> Option<Vec2> {
+        match *self {
+            Obstacle::Rect { x, y, width, height } => {
+                let closest = Vec2 { x: center.x.clamp(x, x + width), y: center.y.clamp(y, y + height) };
+                let offset = Vec2 { x: center.x - closest.x, y: center.y - closest.y };
+                let distance = offset.length();
+                if distance >= radius {
+                    return None;
+                }
+                if distance > 0.0 {
+                    return Some(closest.sum(&offset.scale(radius / distance)));
+                }
+                // The center is inside, so leave through the nearest side.
+                let exits = [
+                    (center.x - x, Vec2 { x: x - radius, y: center.y }),
+                    (x + width - center.x, Vec2 { x: x + width + radius, y: center.y }),
+                    (center.y - y, Vec2 { x: center.x, y: y - radius }),
+                    (y + height - center.y, Vec2 { x: center.x, y: y + height + radius })
+                ];
+                exits.into_iter()
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .map(|(_, exit)| exit)
+            },
+            Obstacle::Circle { x, y, radius: own_radius } => {
+                let offset = Vec2 { x: center.x - x, y: center.y - y };
+                let distance = offset.length();
+                let min_distance = radius + own_radius;
+                if distance >= min_distance {
+                    return None;
+                }
+                let direction = if distance > 0.0 { offset.scale(1.0 / distance) } else { Vec2 { x: 1.0, y: 0.0 } };
+                Some(Vec2 { x, y }.sum(&direction.scale(min_distance)))
+            }
+        }
+    }
+
+    pub fn overlaps(&self, center: &Vec2, radius: f32) -> bool {
+        self.push_out(center, radius).is_some()
+    }
+
+    /// How far along the segment from `from` to `to` a circle moving on it
+    /// first overlaps the obstacle, as a fraction in `[0, 1]`, or `None` if
+    /// it never does.
+    pub fn sweep(&self, from: &Vec2, to: &Vec2, radius: f32) -> Option<f32> {
+        match *self {
+            Obstacle::Rect { x, y, width, height } => {
+                if self.overlaps(from, radius) {
+                    return Some(0.0);
+                }
+                // Where the segment enters the rect grown by `radius`...
+                let axes = [(from.x, to.x - from.x, x - radius, x + width + radius), (from.y, to.y - from.y, y - radius, y + height + radius)];
+                let (mut enter, mut exit) = (0.0f32, 1.0f32);
+                for (start, delta, low, high) in axes {
+                    if delta == 0.0 {
+                        if start <= low || start >= high {
+                            return None;
+                        }
+                        continue;
+                    }
+                    let (a, b) = ((low - start) / delta, (high - start) / delta);
+                    enter = enter.max(a.min(b));
+                    exit = exit.min(a.max(b));
+                }
+                if enter >= exit {
+                    return None;
+                }
+                // ...unless that is next to a corner, which is rounded.
+                let point = Vec2 { x: from.x + (to.x - from.x) * enter, y: from.y + (to.y - from.y) * enter };
+                let corner_x = if point.x < x { Some(x) } else if point.x > x + width { Some(x + width) } else { None };
+                let corner_y = if point.y < y { Some(y) } else if point.y > y + height { Some(y + height) } else { None };
+                match (corner_x, corner_y) {
+                    (Some(x), Some(y)) => sweep_circle(from, to, &Vec2 { x, y }, radius),
+                    _ => Some(enter)
+                }
+            },
+            Obstacle::Circle { x, y, radius: own_radius } => sweep_circle(from, to, &Vec2 { x, y }, radius + own_radius)
+        }
+    }
+}
+
+impl Map {
+    pub fn from_json(text: &str) -> Result<Self, MapError> {
+        let map: Map = serde_json::from_str(text).map_err(|e| MapError::Parse(e.to_string()))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    pub fn from_ron(text: &str) -> Result<Self, MapError> {
+        let map: Map = ron::from_str(text).map_err(|e| MapError::Parse(e.to_string()))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    /// Reads a `.json` file as JSON and anything else as RON.
+    pub fn from_file(path: &Path) -> Result<Self, MapError> {
+        let text = std::fs::read_to_string(path).map_err(|e| MapError::Read(path.to_path_buf(), e))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&text),
+            _ => Self::from_ron(&text)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), MapError> {
+        if !(self.width > 0.0 && self.height > 0.0 && self.width.is_finite() && self.height.is_finite()) {
+            return Err(MapError::Invalid("width and height must be positive".to_string()));
+        }
+        if self.spawn_points.is_empty() {
+            return Err(MapError::Invalid("there must be at least one spawn point".to_string()));
+        }
+        if let Some(point) = self.spawn_points.iter().find(|point| self.blocks(point, PLAYER_RADIUS_SIZE)) {
+            return Err(MapError::Invalid(format!("spawn point ({}, {}) is too close to a wall or obstacle", point.x, point.y)));
+        }
+        self.pickups.iter().try_for_each(|pickup| {
+            if self.blocks(&pickup.position, PICKUP_RADIUS_SIZE) {
+                let Vec2 { x, y } = pickup.position;
+                return Err(MapError::Invalid(format!("pickup at ({}, {}) is inside a wall or obstacle", x, y)));
+            }
+            pickup.validate().map_err(MapError::Invalid)
+        })
+    }
+
+    /// Moves a circle at `center` out of every obstacle and back into the
+    /// arena. Obstacles are resolved one after the other, a few times over,
+    /// which is enough for shapes that don't overlap much.
+    pub fn resolve(&self, center: &Vec2, radius: f32) -> Vec2 {
+        let mut position = center.clone();
+        for _ in 0..3 {
+            let mut moved = false;
+            self.obstacles.iter().for_each(|obstacle| {
+                if let Some(out) = obstacle.push_out(&position, radius) {
+                    position = out;
+                    moved = true;
+                }
+            });
+            position = self.clamp(&position, radius);
+            if !moved {
+                break;
+            }
+        }
+        position
+    }
+
+    /// Whether a circle at `center` is outside the arena or touches an
+    /// obstacle.
+    pub fn blocks(&self, center: &Vec2, radius: f32) -> bool {
+        self.clamp(center, radius) != *center || self.obstacles.iter().any(|obstacle| obstacle.overlaps(center, radius))
+    }
+
+    /// How far along the segment from `from` to `to` a circle moving on it
+    /// is first blocked, as a fraction in `[0, 1]`, or `None` if it never is.
+    /// Unlike `blocks` at the end point, this catches circles that pass
+    /// through a thin obstacle within one move.
+    pub fn first_block(&self, from: &Vec2, to: &Vec2, radius: f32) -> Option<f32> {
+        let radius_x = radius.min(self.width / 2.0);
+        let radius_y = radius.min(self.height / 2.0);
+        let axes = [(from.x, to.x, radius_x, self.width - radius_x), (from.y, to.y, radius_y, self.height - radius_y)];
+        let leaves = axes.into_iter().filter_map(|(start, end, low, high)| {
+            if start < low || start > high {
+                Some(0.0)
+            } else if end < low {
+                Some((low - start) / (end - start))
+            } else if end > high {
+                Some((high - start) / (end - start))
+            } else {
+                None
+            }
+        });
+        self.obstacles.iter()
+            .filter_map(|obstacle| obstacle.sweep(from, to, radius))
+            .chain(leaves)
+            .min_by(f32::total_cmp)
+    }
+
+    fn clamp(&self, center: &Vec2, radius: f32) -> Vec2 {
+        let radius_x = radius.min(self.width / 2.0);
+        let radius_y = radius.min(self.height / 2.0);
+        Vec2 {
+            x: center.x.clamp(radius_x, self.width - radius_x),
+            y: center.y.clamp(radius_y, self.height - radius_y)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn map_files_are_read() {
+        let map = Map::from_ron(include_str!("../maps/pillars.ron")).unwrap();
+        assert_eq!(map.obstacles.len(), 5);
+        assert_eq!(map.pickups.len(), 5);
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(Map::from_json(&json).unwrap(), map);
+
+        let outside = r#"{"width": 100.0, "height": 100.0, "spawn_points": [{"x": 150.0, "y": 50.0}]}"#;
+        assert!(matches!(Map::from_json(outside), Err(MapError::Invalid(_))));
+        assert!(matches!(Map::from_ron("(width: 1.0)"), Err(MapError::Parse(_))));
+    }
+
+    #[test]
+    fn circles_are_pushed_out_and_kept_in() {
+        let map = Map {
+            width: 200.0,
+            height: 200.0,
+            obstacles: vec![
+                Obstacle::Rect { x: 50.0, y: 50.0, width: 50.0, height: 50.0 },
+                Obstacle::Circle { x: 150.0, y: 150.0, radius: 20.0 }
+            ],
+            spawn_points: vec![Vec2 { x: 10.0, y: 10.0 }],
+            pickups: Vec::new()
+        };
+        // Against the left side of the rect, only x changes.
+        assert_eq!(map.resolve(&Vec2 { x: 45.0, y: 70.0 }, 10.0), Vec2 { x: 40.0, y: 70.0 });
+        // Inside it, out through the nearest side.
+        assert_eq!(map.resolve(&Vec2 { x: 95.0, y: 70.0 }, 10.0), Vec2 { x: 110.0, y: 70.0 });
+        let out = map.resolve(&Vec2 { x: 150.0, y: 125.0 }, 10.0);
+        assert!((out.y - 120.0).abs() < 1e-4 && (out.x - 150.0).abs() < 1e-4);
+        assert_eq!(map.resolve(&Vec2 { x: -20.0, y: 250.0 }, 10.0), Vec2 { x: 10.0, y: 190.0 });
+
+        assert!(map.blocks(&Vec2 { x: 75.0, y: 49.0 }, 3.0));
+        assert!(map.blocks(&Vec2 { x: 199.0, y: 10.0 }, 3.0));
+        assert!(!map.blocks(&Vec2 { x: 20.0, y: 20.0 }, 3.0));
+    }
+
+    #[test]
+    fn moving_circles_are_stopped_by_what_they_pass_through() {
+        let map = Map {
+            width: 200.0,
+            height: 200.0,
+            obstacles: vec![
+                Obstacle::Rect { x: 50.0, y: 50.0, width: 2.0, height: 50.0 },
+                Obstacle::Circle { x: 150.0, y: 150.0, radius: 20.0 }
+            ],
+            spawn_points: vec![Vec2 { x: 10.0, y: 10.0 }],
+            pickups: Vec::new()
+        };
+        let sweep = |from: (f32, f32), to: (f32, f32)| map.first_block(&Vec2 { x: from.0, y: from.1 }, &Vec2 { x: to.0, y: to.1 }, 2.0);
+        // Straight through the thin wall, whose ends are both clear of it.
+        assert_eq!(sweep((20.0, 70.0), (120.0, 70.0)), Some(0.28));
+        assert_eq!(sweep((20.0, 70.0), (40.0, 70.0)), None);
+        // Past the rounded corner, just missing it, and into the circle.
+        assert_eq!(sweep((20.0, 46.0), (120.0, 46.0)), None);
+        let t = sweep((150.0, 100.0), (150.0, 200.0)).unwrap();
+        assert!((t - 0.28).abs() < 1e-5);
+        // Out of the arena, or already blocked.
+        assert_eq!(sweep((20.0, 20.0), (-80.0, 20.0)), Some(0.18));
+        assert_eq!(sweep((51.0, 70.0), (51.0, 70.0)), Some(0.0));
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use crate::game_state::Vec2;
+
+pub const PICKUP_RADIUS_SIZE: f32 = 8.0;
+/// How much faster players under `EffectKind::SpeedBoost` move.
+pub const SPEED_BOOST_FACTOR: f32 = 1.5;
+/// Share of bullet damage players under `EffectKind::Shield` still take.
+pub const SHIELD_DAMAGE_FACTOR: f32 = 0.5;
+pub const DEFAULT_PICKUP_RESPAWN_SECS: f32 = 15.0;
+
+/// What walking over a pickup does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PickupKind {
+    /// Heals up to the maximum health. Left alone by players at full health.
+    Health { amount: i32 },
+    /// Fills every magazine and ends any reload.
+    Ammo,
+    SpeedBoost { secs: f32 },
+    Shield { secs: f32 },
+    /// A loaded copy of the named weapon, which is taken out right away.
+    WeaponCrate { weapon: String }
+}
+
+/// Where a map places a pickup, and how long it takes to come back once
+/// collected, e.g. `(position: (x: 400.0, y: 200.0), kind: Shield(secs: 10.0))`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PickupSpawn {
+    pub position: Vec2,
+    pub kind: PickupKind,
+    #[serde(default = "default_respawn_secs")]
+    pub respawn_secs: f32
+}
+
+fn default_respawn_secs() -> f32 {
+    DEFAULT_PICKUP_RESPAWN_SECS
+}
+
+/// A pickup lying in the arena.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pickup {
+    pub kind: PickupKind,
+    pub position: Vec2,
+    /// Index of the spawn in `Map::pickups` it came from.
+    pub spawn: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EffectKind {
+    SpeedBoost,
+    Shield
+}
+
+/// A timed effect on a player.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusEffect {
+    pub kind: EffectKind,
+    /// Seconds until it wears off.
+    pub remaining: f32
+}
+
+impl PickupKind {
+    /// The effect it puts on the player, and for how long.
+    pub fn effect(&self) -> Option<StatusEffect> {
+        match *self {
+            PickupKind::SpeedBoost { secs } => Some(StatusEffect { kind: EffectKind::SpeedBoost, remaining: secs }),
+            PickupKind::Shield { secs } => Some(StatusEffect { kind: EffectKind::Shield, remaining: secs }),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            PickupKind::Health { .. } => "health",
+            PickupKind::Ammo => "ammo",
+            PickupKind::SpeedBoost { .. } => "speed",
+            PickupKind::Shield { .. } => "shield",
+            PickupKind::WeaponCrate { weapon } => weapon
+        }
+    }
+}
+
+impl EffectKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectKind::SpeedBoost => "speed",
+            EffectKind::Shield => "shield"
+        }
+    }
+}
+
+impl PickupSpawn {
+    pub fn validate(&self) -> Result<(), String> {
+        let durations = match self.kind {
+            PickupKind::SpeedBoost { secs } | PickupKind::Shield { secs } => vec![secs, self.respawn_secs],
+            _ => vec![self.respawn_secs]
+        };
+        if !durations.into_iter().all(|secs| secs.is_finite() && secs >= 0.0) {
+            return Err(format!("{} pickup: durations must be finite and not negative", self.kind.name()));
+        }
+        if matches!(self.kind, PickupKind::Health { amount } if amount <= 0) {
+            return Err("health pickup: amount must be positive".to_string());
+        }
+        Ok(())
+    }
+}
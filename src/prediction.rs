@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use crate::game_state::{GameEvent, GameState, TICK_DT};
+use crate::ws::{Commands, Input};
+
+#[derive(Debug, Clone)]
+pub struct PendingInput {
+    pub seq: u32,
+    pub tick: u64,
+    pub command: Commands
+}
+
+/// Client-side prediction for the local player. Inputs are applied to the
+/// local `GameState` as soon as they are made and kept until the server
+/// acknowledges them through `PlayerState::last_input_seq`.
+pub struct Prediction {
+    player_name: String,
+    next_seq: u32,
+    pending: VecDeque<PendingInput>,
+    tick_dt: f32
+}
+
+impl Prediction {
+    pub fn new(player_name: &str) -> Self {
+        Prediction { player_name: player_name.to_string(), next_seq: 0, pending: VecDeque::with_capacity(64), tick_dt: TICK_DT }
+    }
+
+    /// Sets the length of a replayed tick, to match the server's tick rate.
+    pub fn set_tick_dt(&mut self, tick_dt: f32) {
+        self.tick_dt = tick_dt;
+    }
+
+    /// Applies `command` to the local state right away and returns the
+    /// sequenced `Input` to send to the server.
+    pub fn apply(&mut self, state: &mut GameState, command: Commands) -> Input {
+        self.next_seq += 1;
+        let input = PendingInput { seq: self.next_seq, tick: state.tick, command };
+        self.apply_to(state, &input);
+        self.pending.push_back(input.clone());
+        Input { seq: input.seq, command: input.command }
+    }
+
+    /// Rewinds `state` to the authoritative snapshot, drops every input the
+    /// server has processed and replays the rest up to the local tick.
+    pub fn reconcile(&mut self, state: &mut GameState, authoritative: GameState) {
+        match authoritative.players.get(&self.player_name) {
+            Some(player) => {
+                let acked = player.last_input_seq;
+                self.pending.retain(|input| input.seq > acked);
+            },
+            None => self.pending.clear()
+        }
+
+        let target = state.tick.max(authoritative.tick);
+        *state = authoritative;
+        let mut replay = self.pending.iter().peekable();
+        loop {
+            while let Some(input) = replay.next_if(|input| input.tick <= state.tick) {
+                self.apply_to(state, input);
+            }
+            if state.tick >= target {
+                break;
+            }
+            state.step(self.tick_dt);
+        }
+    }
+
+    /// Whether `event` is the server echoing one of our own inputs, which
+    /// has already been applied locally and must not be applied twice.
+    pub fn is_predicted(&self, event: &GameEvent) -> bool {
+        match event {
+            GameEvent::Shooting(name) => *name == self.player_name,
+            GameEvent::UpdateVelocity { name, .. } | GameEvent::UpdateAngle { name, .. } |
+            GameEvent::SwitchWeapon { name, .. } => *name == self.player_name,
+            _ => false
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn apply_to(&self, state: &mut GameState, input: &PendingInput) {
+        if !state.players.contains_key(&self.player_name) {
+            return;
+        }
+        if let Ok(event) = input.command.clone().into_event(&self.player_name) {
+            state.react_to_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::game_state::{PlayerInput, Vec2};
+
+    fn game() -> GameState {
+        let mut game = GameState::new();
+        game.add_player("pl", Vec2 { x: 0.0, y: 0.0 });
+        game
+    }
+
+    fn to_server(input: Input) -> PlayerInput {
+        input.into_player_input("pl").unwrap()
+    }
+
+    #[test]
+    fn input_is_applied_immediately() {
+        let mut client = game();
+        let mut prediction = Prediction::new("pl");
+        let input = prediction.apply(&mut client, Commands::UpdateVelocity { x: 30.0, y: 0.0 });
+        assert_eq!(input.seq, 1);
+        assert_eq!(client.players["pl"].velocity.x, 30.0);
+        assert_eq!(prediction.pending(), 1);
+    }
+
+    #[test]
+    fn unacknowledged_inputs_are_replayed() {
+        let mut server = game();
+        let mut client = game();
+        let mut prediction = Prediction::new("pl");
+
+        let first = prediction.apply(&mut client, Commands::UpdateVelocity { x: 30.0, y: 0.0 });
+        server.apply_input(to_server(first));
+        for _ in 0..5 {
+            client.step(TICK_DT);
+            server.step(TICK_DT);
+        }
+        let second = prediction.apply(&mut client, Commands::UpdateVelocity { x: 0.0, y: 30.0 });
+        for _ in 0..5 {
+            client.step(TICK_DT);
+        }
+        let predicted = client.players["pl"].position.clone();
+
+        // The snapshot was taken before the server saw the second input.
+        prediction.reconcile(&mut client, server.clone());
+        assert_eq!(prediction.pending(), 1);
+        assert_eq!(client.tick, 10);
+        assert!((client.players["pl"].position.x - predicted.x).abs() < 1e-4);
+        assert!((client.players["pl"].position.y - predicted.y).abs() < 1e-4);
+
+        server.apply_input(to_server(second));
+        for _ in 0..5 {
+            server.step(TICK_DT);
+        }
+        prediction.reconcile(&mut client, server);
+        assert_eq!(prediction.pending(), 0);
+        assert!((client.players["pl"].position.y - predicted.y).abs() < 1e-4);
+    }
+
+    #[test]
+    fn own_echoes_are_skipped() {
+        let prediction = Prediction::new("pl");
+        assert!(prediction.is_predicted(&GameEvent::Shooting("pl".to_string())));
+        assert!(!prediction.is_predicted(&GameEvent::Shooting("other".to_string())));
+        assert!(!prediction.is_predicted(&GameEvent::Death { name: "pl".to_string(), killer: None }));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::codec;
+
+/// Bumped whenever `GameEvent`, `ClientMessage` or anything they carry
+/// changes shape, so that mismatched clients are turned away up front.
+pub const PROTOCOL_VERSION: u32 = 13;
+pub const BUILD: &str = env!("CARGO_PKG_VERSION");
+pub const SUPPORTED_CODECS: [&str; 2] = [codec::BINARY, codec::JSON];
+
+/// Close codes sent when the handshake fails, from the range the WebSocket
+/// spec leaves to applications.
+pub const CLOSE_VERSION_MISMATCH: u16 = 4001;
+pub const CLOSE_NO_COMMON_CODEC: u16 = 4002;
+pub const CLOSE_BAD_HANDSHAKE: u16 = 4003;
+
+/// First message on every connection, always sent as JSON text.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub client_build: String,
+    /// Codecs the client can speak, most preferred first.
+    pub codecs: Vec<String>
+}
+
+/// The server's answer to `Hello`, also JSON text. Everything after it is
+/// encoded with `codec`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub protocol_version: u32,
+    pub server_build: String,
+    pub codec: String,
+    pub player_id: String,
+    /// Simulation ticks per second, which the client has to step its
+    /// predicted state at too.
+    pub tick_rate: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    VersionMismatch { client: u32, server: u32 },
+    NoCommonCodec(Vec<String>),
+    Malformed(String)
+}
+
+impl HandshakeError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            HandshakeError::VersionMismatch { .. } => CLOSE_VERSION_MISMATCH,
+            HandshakeError::NoCommonCodec(_) => CLOSE_NO_COMMON_CODEC,
+            HandshakeError::Malformed(_) => CLOSE_BAD_HANDSHAKE
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::VersionMismatch { client, server } =>
+                write!(f, "protocol version mismatch: client speaks v{}, server speaks v{}", client, server),
+            HandshakeError::NoCommonCodec(codecs) =>
+                write!(f, "no common codec: client offered {:?}, server supports {:?}", codecs, SUPPORTED_CODECS),
+            HandshakeError::Malformed(e) => write!(f, "bad handshake: {}", e)
+        }
+    }
+}
+
+impl Hello {
+    pub fn new(codecs: &[&str]) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: BUILD.to_string(),
+            codecs: codecs.iter().map(|codec| codec.to_string()).collect()
+        }
+    }
+
+    pub fn parse(msg: &str) -> Result<Hello, HandshakeError> {
+        serde_json::from_str(msg).map_err(|e| HandshakeError::Malformed(e.to_string()))
+    }
+
+    /// Checks the client's hello and picks the codec for the connection:
+    /// `preferred` if the client offered it, otherwise the first codec in the
+    /// client's list that the server also supports.
+    pub fn accept(&self, preferred: Option<&str>, player_id: &str, tick_rate: u32) -> Result<Welcome, HandshakeError> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(HandshakeError::VersionMismatch { client: self.protocol_version, server: PROTOCOL_VERSION });
+        }
+        let offered = |name: &&str| self.codecs.iter().any(|codec| codec == name);
+        let codec = preferred
+            .filter(|name| offered(name) && SUPPORTED_CODECS.contains(name))
+            .or_else(|| self.codecs.iter().map(String::as_str).find(|name| SUPPORTED_CODECS.contains(name)))
+            .ok_or_else(|| HandshakeError::NoCommonCodec(self.codecs.clone()))?;
+        Ok(Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            server_build: BUILD.to_string(),
+            codec: codec.to_string(),
+            player_id: player_id.to_string(),
+            tick_rate
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn matching_hello_is_welcomed() {
+        let welcome = Hello::new(&[codec::JSON, codec::BINARY]).accept(None, "pl", 30).unwrap();
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(welcome.codec, codec::JSON);
+        assert_eq!(welcome.player_id, "pl");
+        assert_eq!(welcome.tick_rate, 30);
+
+        let welcome = Hello::new(&[codec::JSON, codec::BINARY]).accept(Some(codec::BINARY), "pl", 30).unwrap();
+        assert_eq!(welcome.codec, codec::BINARY);
+
+        let welcome = Hello::new(&["xml", codec::JSON]).accept(Some(codec::BINARY), "pl", 30).unwrap();
+        assert_eq!(welcome.codec, codec::JSON);
+    }
+
+    #[test]
+    fn version_mismatch_is_rejected() {
+        let mut hello = Hello::new(&[codec::JSON]);
+        hello.protocol_version = PROTOCOL_VERSION + 1;
+        let err = hello.accept(None, "pl", 30).unwrap_err();
+        assert_eq!(err.close_code(), CLOSE_VERSION_MISMATCH);
+        assert!(err.to_string().contains("version mismatch"));
+    }
+
+    #[test]
+    fn bad_hellos_are_rejected() {
+        let err = Hello::new(&["xml"]).accept(None, "pl", 30).unwrap_err();
+        assert_eq!(err.close_code(), CLOSE_NO_COMMON_CODEC);
+
+        let err = Hello::parse(r#"{"Input":{"seq":1,"command":"Shoot"}}"#).unwrap_err();
+        assert_eq!(err.close_code(), CLOSE_BAD_HANDSHAKE);
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::map::Map;
+use crate::entity::EntityId;
+use crate::weapon::{Weapon, WeaponState};
+use crate::pickup::{Pickup, StatusEffect};
+use crate::game_state::{Action, BulletState, GameEvent, GameState, MatchState, PlayerState, Score, Vec2};
+
+/// How many snapshots a connection remembers. A client whose last
+/// acknowledged snapshot has fallen out of this window gets a full sync.
+pub const SNAPSHOT_HISTORY: usize = 32;
+
+/// The fields of a player that changed since the base snapshot; `None`
+/// means unchanged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    pub name: String,
+    pub position: Option<Vec2>,
+    pub velocity: Option<Vec2>,
+    pub angle: Option<f32>,
+    pub health: Option<i32>,
+    pub alive: Option<bool>,
+    pub respawn_tick: Option<u64>,
+    pub protected_until: Option<u64>,
+    pub last_input_seq: Option<u32>,
+    #[serde(default)]
+    pub weapons: Option<Vec<WeaponState>>,
+    #[serde(default)]
+    pub current_weapon: Option<usize>,
+    #[serde(default)]
+    pub effects: Option<Vec<StatusEffect>>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulletDelta {
+    pub id: EntityId,
+    pub position: Vec2,
+    pub time: f32
+}
+
+/// Everything that changed between the snapshot at `base_tick` and the one
+/// at `tick`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateDelta {
+    pub base_tick: u64,
+    pub tick: u64,
+    pub players_added: Vec<PlayerState>,
+    pub players_removed: Vec<String>,
+    pub players_changed: Vec<PlayerDelta>,
+    pub bullets_spawned: Vec<(EntityId, BulletState)>,
+    pub bullets_despawned: Vec<EntityId>,
+    pub bullets_moved: Vec<BulletDelta>,
+    pub actions: Vec<Action>,
+    /// Scores that are new or changed; scores are never removed.
+    #[serde(default)]
+    pub scores_changed: BTreeMap<String, Score>,
+    #[serde(default)]
+    pub match_state: Option<MatchState>,
+    /// Only set if the map was swapped out, which normal games never do.
+    #[serde(default)]
+    pub map: Option<Arc<Map>>,
+    /// Like `map`, only set if the weapon definitions were swapped out.
+    #[serde(default)]
+    pub weapons: Option<Arc<Vec<Weapon>>>,
+    /// Pickups never move, so they only come and go.
+    #[serde(default)]
+    pub pickups_spawned: Vec<(EntityId, Pickup)>,
+    #[serde(default)]
+    pub pickups_despawned: Vec<EntityId>,
+    #[serde(default)]
+    pub pickup_respawns: Option<BTreeMap<usize, f32>>
+}
+
+fn changed<T: PartialEq + Clone>(base: &T, current: &T) -> Option<T> {
+    if base != current {
+        Some(current.clone())
+    } else {
+        None
+    }
+}
+
+impl StateDelta {
+    pub fn diff(base: &GameState, current: &GameState) -> Self {
+        let mut players_added = Vec::new();
+        let mut players_changed = Vec::new();
+        current.players.iter().for_each(|(name, player)| match base.players.get(name) {
+            None => players_added.push(player.clone()),
+            Some(old) => {
+                let delta = PlayerDelta {
+                    name: name.clone(),
+                    position: changed(&old.position, &player.position),
+                    velocity: changed(&old.velocity, &player.velocity),
+                    angle: changed(&old.angle, &player.angle),
+                    health: changed(&old.health, &player.health),
+                    alive: changed(&old.alive, &player.alive),
+                    respawn_tick: changed(&old.respawn_tick, &player.respawn_tick),
+                    protected_until: changed(&old.protected_until, &player.protected_until),
+                    last_input_seq: changed(&old.last_input_seq, &player.last_input_seq),
+                    weapons: changed(&old.weapons, &player.weapons),
+                    current_weapon: changed(&old.current_weapon, &player.current_weapon),
+                    effects: changed(&old.effects, &player.effects)
+                };
+                if !delta.is_empty() {
+                    players_changed.push(delta);
+                }
+            }
+        });
+        let players_removed = base.players.keys()
+            .filter(|name| !current.players.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let mut bullets_spawned = Vec::new();
+        let mut bullets_moved = Vec::new();
+        current.bullets.iter().for_each(|(id, bullet)| match base.bullets.get(id) {
+            None => bullets_spawned.push((id, bullet.clone())),
+            Some(old) => if old.position != bullet.position || old.time != bullet.time {
+                bullets_moved.push(BulletDelta { id, position: bullet.position.clone(), time: bullet.time });
+            }
+        });
+        let bullets_despawned = base.bullets.ids()
+            .filter(|id| !current.bullets.contains(*id))
+            .collect();
+
+        let pickups_spawned = current.pickups.iter()
+            .filter(|(id, _)| !base.pickups.contains(*id))
+            .map(|(id, pickup)| (id, pickup.clone()))
+            .collect();
+        let pickups_despawned = base.pickups.ids()
+            .filter(|id| !current.pickups.contains(*id))
+            .collect();
+
+        let scores_changed = current.scores.iter()
+            .filter(|(name, score)| base.scores.get(*name) != Some(*score))
+            .map(|(name, score)| (name.clone(), score.clone()))
+            .collect();
+
+        StateDelta {
+            base_tick: base.tick,
+            tick: current.tick,
+            players_added,
+            players_removed,
+            players_changed,
+            bullets_spawned,
+            bullets_despawned,
+            bullets_moved,
+            actions: current.actions.clone(),
+            scores_changed,
+            match_state: changed(&base.match_state, &current.match_state),
+            map: (!Arc::ptr_eq(&base.map, &current.map) && base.map != current.map).then(|| current.map.clone()),
+            weapons: (!Arc::ptr_eq(&base.weapons, &current.weapons) && base.weapons != current.weapons).then(|| current.weapons.clone()),
+            pickups_spawned,
+            pickups_despawned,
+            pickup_respawns: changed(&base.pickup_respawns, &current.pickup_respawns)
+        }
+    }
+
+    /// Rebuilds the snapshot at `self.tick` from the one at `self.base_tick`.
+    pub fn apply(&self, base: &GameState) -> GameState {
+        let mut state = base.clone();
+        state.tick = self.tick;
+        state.actions = self.actions.clone();
+        state.scores.extend(self.scores_changed.clone());
+        if let Some(match_state) = &self.match_state {
+            state.match_state = match_state.clone();
+        }
+        if let Some(map) = &self.map {
+            state.map = map.clone();
+        }
+        if let Some(weapons) = &self.weapons {
+            state.weapons = weapons.clone();
+        }
+        if let Some(respawns) = &self.pickup_respawns {
+            state.pickup_respawns = respawns.clone();
+        }
+        self.pickups_despawned.iter().for_each(|id| {
+            state.pickups.remove(*id);
+        });
+        self.pickups_spawned.iter().for_each(|(id, pickup)| {
+            state.pickups.insert_at(*id, pickup.clone());
+        });
+
+        self.players_removed.iter().for_each(|name| {
+            state.players.remove(name);
+        });
+        self.players_added.iter().for_each(|player| {
+            state.players.insert(player.name.clone(), player.clone());
+        });
+        self.players_changed.iter().for_each(|delta| {
+            if let Some(player) = state.players.get_mut(&delta.name) {
+                delta.apply(player);
+            }
+        });
+
+        self.bullets_despawned.iter().for_each(|id| {
+            state.bullets.remove(*id);
+        });
+        self.bullets_moved.iter().for_each(|delta| {
+            if let Some(bullet) = state.bullets.get_mut(delta.id) {
+                bullet.position = delta.position.clone();
+                bullet.time = delta.time;
+            }
+        });
+        self.bullets_spawned.iter().for_each(|(id, bullet)| {
+            state.bullets.insert_at(*id, bullet.clone());
+        });
+        state
+    }
+}
+
+impl PlayerDelta {
+    fn is_empty(&self) -> bool {
+        self.position.is_none() && self.velocity.is_none() && self.angle.is_none() &&
+            self.health.is_none() && self.alive.is_none() && self.respawn_tick.is_none() &&
+            self.protected_until.is_none() && self.last_input_seq.is_none() &&
+            self.weapons.is_none() && self.current_weapon.is_none() && self.effects.is_none()
+    }
+
+    fn apply(&self, player: &mut PlayerState) {
+        if let Some(position) = &self.position {
+            player.position = position.clone();
+        }
+        if let Some(velocity) = &self.velocity {
+            player.velocity = velocity.clone();
+        }
+        if let Some(angle) = self.angle {
+            player.angle = angle;
+        }
+        if let Some(health) = self.health {
+            player.health = health;
+        }
+        if let Some(alive) = self.alive {
+            player.alive = alive;
+        }
+        if let Some(tick) = self.respawn_tick {
+            player.respawn_tick = tick;
+        }
+        if let Some(tick) = self.protected_until {
+            player.protected_until = tick;
+        }
+        if let Some(seq) = self.last_input_seq {
+            player.last_input_seq = seq;
+        }
+        if let Some(weapons) = &self.weapons {
+            player.weapons = weapons.clone();
+        }
+        if let Some(slot) = self.current_weapon {
+            player.current_weapon = slot;
+        }
+        if let Some(effects) = &self.effects {
+            player.effects = effects.clone();
+        }
+    }
+}
+
+/// The last few snapshots sent or received on a connection, by tick.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<GameState>
+}
+
+impl SnapshotHistory {
+    pub fn new() -> Self {
+        SnapshotHistory { snapshots: VecDeque::with_capacity(SNAPSHOT_HISTORY) }
+    }
+
+    pub fn insert(&mut self, state: GameState) {
+        if self.snapshots.len() == SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(state);
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&GameState> {
+        self.snapshots.iter().rev().find(|state| state.tick == tick)
+    }
+}
+
+impl Default for SnapshotHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Server side of delta compression for one connection. Snapshots go out as
+/// deltas against the last one the client acknowledged, or in full when
+/// there is no usable baseline (right after joining, or when the client has
+/// fallen more than `SNAPSHOT_HISTORY` snapshots behind).
+#[derive(Default)]
+pub struct DeltaEncoder {
+    history: SnapshotHistory,
+    acked: Option<u64>
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ack(&mut self, tick: u64) {
+        if self.acked.is_none_or(|acked| tick > acked) {
+            self.acked = Some(tick);
+        }
+    }
+
+    pub fn encode(&mut self, state: GameState) -> GameEvent {
+        let base = self.acked.and_then(|tick| self.history.get(tick));
+        let event = match base {
+            Some(base) => GameEvent::GameStateDelta(StateDelta::diff(base, &state)),
+            None => GameEvent::GameStateSync(state.clone())
+        };
+        self.history.insert(state);
+        event
+    }
+}
+
+/// Client side of delta compression: turns full syncs and deltas back into
+/// complete snapshots. The caller acknowledges every snapshot it gets back.
+#[derive(Default)]
+pub struct DeltaDecoder {
+    history: SnapshotHistory
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the full snapshot carried by a `GameStateSync` or
+    /// `GameStateDelta`, or `None` for other events and for deltas whose
+    /// base is no longer known.
+    pub fn decode(&mut self, event: &GameEvent) -> Option<GameState> {
+        let state = match event {
+            GameEvent::GameStateSync(state) => state.clone(),
+            GameEvent::GameStateDelta(delta) => delta.apply(self.history.get(delta.base_tick)?),
+            _ => return None
+        };
+        self.history.insert(state.clone());
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::game_state::TICK_DT;
+
+    fn json(state: &GameState) -> String {
+        serde_json::to_string(state).unwrap()
+    }
+
+    fn simulate() -> Vec<GameState> {
+        let mut game = GameState::new();
+        game.add_player("a", Vec2 { x: 0.0, y: 0.0 });
+        game.add_player("b", Vec2 { x: 300.0, y: 0.0 });
+        let mut snapshots = vec![game.clone()];
+        for tick in 0..120u64 {
+            match tick {
+                2 => game.react_to_event(GameEvent::UpdateVelocity { x: 10.0, y: 5.0, name: "a".to_string() }),
+                5 | 6 | 40 => game.react_to_event(GameEvent::Shooting("b".to_string())),
+                10 => game.react_to_event(GameEvent::AddPlayer { x: 50.0, y: 50.0, name: "c".to_string() }),
+                30 => game.react_to_event(GameEvent::UpdateAngle { angle: 90.0, name: "c".to_string() }),
+                60 => game.react_to_event(GameEvent::Death { name: "a".to_string(), killer: None }),
+                _ => {}
+            }
+            game.step(TICK_DT);
+            if tick % 3 == 0 {
+                snapshots.push(game.clone());
+            }
+        }
+        snapshots
+    }
+
+    #[test]
+    fn delta_chain_rebuilds_state() {
+        let snapshots = simulate();
+        let mut rebuilt = snapshots[0].clone();
+        for pair in snapshots.windows(2) {
+            let delta = StateDelta::diff(&pair[0], &pair[1]);
+            rebuilt = delta.apply(&rebuilt);
+            assert_eq!(json(&rebuilt), json(&pair[1]));
+        }
+    }
+
+    #[test]
+    fn unchanged_players_are_left_out() {
+        let mut base = GameState::new();
+        base.add_player("idle", Vec2 { x: 50.0, y: 50.0 });
+        base.add_player("moving", Vec2 { x: 150.0, y: 50.0 });
+        let mut current = base.clone();
+        current.react_to_event(GameEvent::UpdateVelocity { x: 10.0, y: 0.0, name: "moving".to_string() });
+        current.step(TICK_DT);
+
+        let delta = StateDelta::diff(&base, &current);
+        assert_eq!(delta.players_changed.len(), 1);
+        assert_eq!(delta.players_changed[0].name, "moving");
+        assert!(delta.players_changed[0].angle.is_none());
+        assert!(serde_json::to_string(&delta).unwrap().len() < json(&current).len());
+    }
+
+    #[test]
+    fn encoder_and_decoder_stay_in_sync() {
+        let snapshots = simulate();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        let first = encoder.encode(snapshots[0].clone());
+        assert!(matches!(first, GameEvent::GameStateSync(_)));
+        let decoded = decoder.decode(&first).unwrap();
+        encoder.ack(decoded.tick);
+
+        for state in &snapshots[1..] {
+            let event = encoder.encode(state.clone());
+            assert!(matches!(event, GameEvent::GameStateDelta(_)));
+            let decoded = decoder.decode(&event).unwrap();
+            assert_eq!(json(&decoded), json(state));
+            encoder.ack(decoded.tick);
+        }
+    }
+
+    #[test]
+    fn lagging_client_gets_full_snapshot() {
+        let snapshots = simulate();
+        let mut encoder = DeltaEncoder::new();
+        encoder.encode(snapshots[0].clone());
+        encoder.ack(snapshots[0].tick);
+        for state in &snapshots[1..=SNAPSHOT_HISTORY] {
+            encoder.encode(state.clone());
+        }
+        let event = encoder.encode(snapshots[SNAPSHOT_HISTORY + 1].clone());
+        assert!(matches!(event, GameEvent::GameStateSync(_)));
+    }
+}
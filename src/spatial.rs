@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+use crate::game_state::{Vec2, PLAYER_RADIUS_SIZE};
+
+/// Cells are a few players wide, so that a player query touches at most four
+/// of them.
+pub const GRID_CELL_SIZE: f32 = 4.0 * PLAYER_RADIUS_SIZE;
+
+/// Uniform grid broadphase over points, rebuilt every tick. Each point is
+/// stored once, in the cell its position falls in, by the index it was
+/// inserted with.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+    /// Lowest and highest cell coordinates holding a point.
+    bounds: Option<((i32, i32), (i32, i32))>
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid { cell_size, cells: HashMap::new(), bounds: None }
+    }
+
+    /// A grid holding each position under its index in `positions`.
+    pub fn build<'a>(cell_size: f32, positions: impl IntoIterator<Item = &'a Vec2>) -> Self {
+        let mut grid = Self::new(cell_size);
+        positions.into_iter().enumerate().for_each(|(index, position)| grid.insert(index, position));
+        grid
+    }
+
+    pub fn insert(&mut self, index: usize, position: &Vec2) {
+        let (x, y) = self.cell_of(position);
+        self.cells.entry((x, y)).or_default().push(index);
+        self.bounds = Some(match self.bounds {
+            Some(((min_x, min_y), (max_x, max_y))) => ((min_x.min(x), min_y.min(y)), (max_x.max(x), max_y.max(y))),
+            None => ((x, y), (x, y))
+        });
+    }
+
+    /// Indices of every point that may be within `radius` of `center`, each
+    /// at most once and in no particular order. Callers still have to check
+    /// the actual distance.
+    pub fn query(&self, center: &Vec2, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let (min_x, min_y) = self.cell_of(&Vec2 { x: center.x - radius, y: center.y - radius });
+        let (max_x, max_y) = self.cell_of(&Vec2 { x: center.x + radius, y: center.y + radius });
+        (min_x..=max_x)
+            .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+
+    /// Indices of every point that may be within `radius` of the segment
+    /// from `from` to `to`, each at most once and in no particular order.
+    /// Only the cells along the part of the segment near any point are
+    /// looked at, so however long it is, it costs no more than crossing the
+    /// area the points are spread over.
+    pub fn query_segment(&self, from: &Vec2, to: &Vec2, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let reach = (radius / self.cell_size).ceil() as i32;
+        let mut cells = HashSet::new();
+        let clipped = self.bounds.and_then(|((min_x, min_y), (max_x, max_y))| {
+            let low = Vec2 { x: (min_x - reach) as f32 * self.cell_size, y: (min_y - reach) as f32 * self.cell_size };
+            let high = Vec2 { x: (max_x + reach + 1) as f32 * self.cell_size, y: (max_y + reach + 1) as f32 * self.cell_size };
+            clip_segment(from, to, &low, &high)
+        });
+        clipped.iter().flat_map(|(from, to)| self.cells_crossed(from, to)).for_each(|(x, y)| {
+            for dx in -reach..=reach {
+                for dy in -reach..=reach {
+                    cells.insert((x + dx, y + dy));
+                }
+            }
+        });
+        cells.into_iter()
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+
+    /// The cells the segment passes through, in order, found by stepping to
+    /// whichever cell border the segment crosses next.
+    fn cells_crossed(&self, from: &Vec2, to: &Vec2) -> impl Iterator<Item = (i32, i32)> {
+        let (mut x, mut y) = self.cell_of(from);
+        let (end_x, end_y) = self.cell_of(to);
+        let axis = |start: f32, delta: f32, cell: i32| {
+            if delta == 0.0 {
+                return (0, f32::INFINITY, f32::INFINITY);
+            }
+            let step = if delta > 0.0 { 1 } else { -1 };
+            let border = (cell + (step + 1) / 2) as f32 * self.cell_size;
+            (step, (border - start) / delta, self.cell_size / delta.abs())
+        };
+        let (step_x, mut next_x, delta_x) = axis(from.x, to.x - from.x, x);
+        let (step_y, mut next_y, delta_y) = axis(from.y, to.y - from.y, y);
+        let steps = (end_x - x).unsigned_abs() + (end_y - y).unsigned_abs();
+        std::iter::once((x, y)).chain((0..steps).map(move |_| {
+            if next_x < next_y {
+                x += step_x;
+                next_x += delta_x;
+            } else {
+                y += step_y;
+                next_y += delta_y;
+            }
+            (x, y)
+        }))
+    }
+
+    fn cell_of(&self, position: &Vec2) -> (i32, i32) {
+        ((position.x / self.cell_size).floor() as i32, (position.y / self.cell_size).floor() as i32)
+    }
+}
+
+/// The part of the segment from `from` to `to` inside the box from `low` to
+/// `high`, if any.
+fn clip_segment(from: &Vec2, to: &Vec2, low: &Vec2, high: &Vec2) -> Option<(Vec2, Vec2)> {
+    let (mut enter, mut exit) = (0.0f32, 1.0f32);
+    for (start, delta, low, high) in [(from.x, to.x - from.x, low.x, high.x), (from.y, to.y - from.y, low.y, high.y)] {
+        if delta == 0.0 {
+            if start < low || start > high {
+                return None;
+            }
+            continue;
+        }
+        let (a, b) = ((low - start) / delta, (high - start) / delta);
+        enter = enter.max(a.min(b));
+        exit = exit.min(a.max(b));
+    }
+    let at = |t: f32| Vec2 { x: from.x + (to.x - from.x) * t, y: from.y + (to.y - from.y) * t };
+    (enter <= exit).then(|| (at(enter), at(exit)))
+}
+
+/// How far along the segment from `from` to `to` a point moving on it first
+/// comes closer than `radius` to `center`, as a fraction in `[0, 1]`, or
+/// `None` if it never does. Only touching the circle isn't a hit.
+pub fn sweep_circle(from: &Vec2, to: &Vec2, center: &Vec2, radius: f32) -> Option<f32> {
+    let start = Vec2 { x: from.x - center.x, y: from.y - center.y };
+    let c = start.x.powi(2) + start.y.powi(2) - radius.powi(2);
+    if c < 0.0 {
+        return Some(0.0);
+    }
+    let direction = Vec2 { x: to.x - from.x, y: to.y - from.y };
+    let a = direction.x.powi(2) + direction.y.powi(2);
+    let b = 2.0 * (start.x * direction.x + start.y * direction.y);
+    let discriminant = b.powi(2) - 4.0 * a * c;
+    if a == 0.0 || discriminant <= 0.0 {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    (0.0..=1.0).contains(&t).then_some(t)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn query_finds_everything_in_range() {
+        let points: Vec<Vec2> = (0..400).map(|i| Vec2 { x: (i % 20) as f32 * 13.0 - 50.0, y: (i / 20) as f32 * 17.0 - 80.0 }).collect();
+        let grid = SpatialGrid::build(GRID_CELL_SIZE, &points);
+        for center in [Vec2 { x: 0.0, y: 0.0 }, Vec2 { x: -45.0, y: 100.0 }, Vec2 { x: 200.0, y: 250.0 }] {
+            let radius = 25.0;
+            let mut found: Vec<usize> = grid.query(&center, radius)
+                .filter(|index| points[*index].distance_squared(&center) <= radius * radius)
+                .collect();
+            found.sort();
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|index| points[*index].distance_squared(&center) <= radius * radius)
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn segment_queries_find_points_along_the_way() {
+        let points = [
+            Vec2 { x: 500_000.0, y: 105.0 },
+            Vec2 { x: 500_000.0, y: 400.0 },
+            Vec2 { x: -30.0, y: 100.0 },
+            Vec2 { x: 123.0, y: 456.0 }
+        ];
+        let grid = SpatialGrid::build(GRID_CELL_SIZE, &points);
+        // A long way, which would be millions of cells as a bounding box.
+        let mut found: Vec<usize> = grid.query_segment(&Vec2 { x: 0.0, y: 100.0 }, &Vec2 { x: 1_000_000.0, y: 110.0 }, 10.0).collect();
+        found.sort();
+        assert!(found.contains(&0) && found.contains(&2));
+        assert!(!found.contains(&1));
+        // Diagonal, backwards, and standing still.
+        assert!(grid.query_segment(&Vec2 { x: 300.0, y: 600.0 }, &Vec2 { x: 0.0, y: 300.0 }, 10.0).any(|index| index == 3));
+        assert_eq!(grid.query_segment(&Vec2 { x: 123.0, y: 456.0 }, &Vec2 { x: 123.0, y: 456.0 }, 1.0).collect::<Vec<usize>>(), [3]);
+    }
+
+    #[test]
+    fn fast_points_hit_circles_they_pass_through() {
+        let center = Vec2 { x: 100.0, y: 0.0 };
+        // Starts and ends far outside on either side.
+        let t = sweep_circle(&Vec2 { x: 0.0, y: 0.0 }, &Vec2 { x: 1000.0, y: 0.0 }, &center, 10.0).unwrap();
+        assert!((t - 0.09).abs() < 1e-5);
+        // Stops short, or moves away.
+        assert_eq!(sweep_circle(&Vec2 { x: 0.0, y: 0.0 }, &Vec2 { x: 89.0, y: 0.0 }, &center, 10.0), None);
+        assert_eq!(sweep_circle(&Vec2 { x: 0.0, y: 0.0 }, &Vec2 { x: -1000.0, y: 0.0 }, &center, 10.0), None);
+        // Already inside, or standing still inside.
+        assert_eq!(sweep_circle(&Vec2 { x: 95.0, y: 0.0 }, &Vec2 { x: 1000.0, y: 0.0 }, &center, 10.0), Some(0.0));
+        assert_eq!(sweep_circle(&Vec2 { x: 95.0, y: 0.0 }, &Vec2 { x: 95.0, y: 0.0 }, &center, 10.0), Some(0.0));
+    }
+
+    #[test]
+    fn grazing_is_not_a_hit() {
+        let center = Vec2 { x: 100.0, y: 0.0 };
+        let from = |y: f32| Vec2 { x: 0.0, y };
+        let to = |y: f32| Vec2 { x: 200.0, y };
+        assert!(sweep_circle(&from(9.9), &to(9.9), &center, 10.0).is_some());
+        assert_eq!(sweep_circle(&from(10.0), &to(10.0), &center, 10.0), None);
+        assert_eq!(sweep_circle(&from(-10.1), &to(-10.1), &center, 10.0), None);
+        // Diagonal, just clipping the edge.
+        let t = sweep_circle(&Vec2 { x: 0.0, y: -100.0 }, &Vec2 { x: 200.0, y: 100.0 }, &Vec2 { x: 107.0, y: 0.0 }, 5.0).unwrap();
+        assert!(t > 0.5 && t < 0.53);
+    }
+}
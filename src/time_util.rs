@@ -8,4 +8,60 @@ pub fn get_current_time() -> f64{
 
     since_the_epoch.as_secs_f64()
 
-}
\ No newline at end of file
+}
+
+/// Turns wall-clock time into a whole number of fixed simulation ticks,
+/// carrying the remainder over to the next call.
+pub struct FixedTimestep {
+    step: f64,
+    accumulator: f64,
+    last_time: f64
+}
+
+impl FixedTimestep {
+    /// Upper bound on ticks returned by one call, so a long stall doesn't
+    /// turn into an endless catch-up loop.
+    const MAX_TICKS: u32 = 8;
+
+    pub fn new(step: f32, now: f64) -> Self {
+        FixedTimestep { step: step as f64, accumulator: 0.0, last_time: now }
+    }
+
+    pub fn ticks(&mut self, now: f64) -> u32 {
+        self.accumulator += (now - self.last_time).max(0.0);
+        self.last_time = now;
+        let mut ticks = 0;
+        while self.accumulator >= self.step && ticks < Self::MAX_TICKS {
+            self.accumulator -= self.step;
+            ticks += 1;
+        }
+        // Only a backlog the cap cut off is dropped, not the remainder.
+        if self.accumulator >= self.step {
+            self.accumulator = 0.0;
+        }
+        ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn fixed_timestep_carries_remainder() {
+        let mut timestep = FixedTimestep::new(0.25, 10.0);
+        assert_eq!(timestep.ticks(10.125), 0);
+        assert_eq!(timestep.ticks(10.625), 2);
+        assert_eq!(timestep.ticks(10.75), 1);
+        assert_eq!(timestep.ticks(20.0), FixedTimestep::MAX_TICKS);
+        assert_eq!(timestep.ticks(20.0), 0);
+    }
+
+    #[test]
+    fn exactly_max_ticks_keeps_the_remainder() {
+        let mut timestep = FixedTimestep::new(0.25, 0.0);
+        assert_eq!(timestep.ticks(2.125), FixedTimestep::MAX_TICKS);
+        assert_eq!(timestep.ticks(2.25), 1);
+    }
+}
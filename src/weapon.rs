@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+/// A kind of gun. Every player carries one of each kind the server is
+/// configured with, which can be changed in the config file, e.g.
+///
+/// ```toml
+/// [[weapons]]
+/// name = "shotgun"
+/// cooldown_secs = 0.8
+/// projectile_speed = 350.0
+/// spread_degrees = 20.0
+/// pellets = 6
+/// damage = 10
+/// magazine = 4
+/// reload_secs = 2.0
+/// projectile_lifetime_secs = 0.6
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Weapon {
+    pub name: String,
+    /// Time between two shots.
+    pub cooldown_secs: f32,
+    pub projectile_speed: f32,
+    /// Angle the pellets of a shot fan out over, centered on the aim.
+    pub spread_degrees: f32,
+    /// Projectiles fired per shot.
+    pub pellets: u32,
+    /// Damage of each projectile.
+    pub damage: i32,
+    /// Shots before the weapon has to be reloaded, which happens on its own
+    /// once it is empty.
+    pub magazine: u32,
+    pub reload_secs: f32,
+    pub projectile_lifetime_secs: f32
+}
+
+/// What a player's copy of a weapon is up to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeaponState {
+    /// Index of the weapon in `GameState::weapons`.
+    pub weapon: usize,
+    pub ammo: u32,
+    /// Seconds until it can fire again.
+    pub cooldown: f32,
+    /// Seconds left of the reload, 0 if it isn't reloading.
+    pub reloading: f32
+}
+
+impl Weapon {
+    pub fn pistol() -> Self {
+        Weapon { name: "pistol".to_string(), cooldown_secs: 0.25, projectile_speed: 400.0, spread_degrees: 0.0, pellets: 1,
+            damage: 25, magazine: 12, reload_secs: 1.5, projectile_lifetime_secs: 2.0 }
+    }
+
+    pub fn shotgun() -> Self {
+        Weapon { name: "shotgun".to_string(), cooldown_secs: 0.8, projectile_speed: 350.0, spread_degrees: 20.0, pellets: 6,
+            damage: 10, magazine: 4, reload_secs: 2.0, projectile_lifetime_secs: 0.6 }
+    }
+
+    pub fn rifle() -> Self {
+        Weapon { name: "rifle".to_string(), cooldown_secs: 0.1, projectile_speed: 600.0, spread_degrees: 0.0, pellets: 1,
+            damage: 15, magazine: 30, reload_secs: 2.5, projectile_lifetime_secs: 2.0 }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let times = [self.cooldown_secs, self.reload_secs];
+        if !times.iter().all(|secs| secs.is_finite() && *secs >= 0.0) {
+            return Err(format!("{}: cooldown and reload time can't be negative", self.name));
+        }
+        let positive = |value: f32| value.is_finite() && value > 0.0;
+        if !positive(self.projectile_speed) || !positive(self.projectile_lifetime_secs) {
+            return Err(format!("{}: projectile speed and lifetime must be positive", self.name));
+        }
+        if !(self.spread_degrees.is_finite() && (0.0..=360.0).contains(&self.spread_degrees)) {
+            return Err(format!("{}: spread must be between 0 and 360 degrees", self.name));
+        }
+        if self.pellets == 0 || self.magazine == 0 || self.damage < 0 {
+            return Err(format!("{}: pellets and magazine must be at least 1 and damage can't be negative", self.name));
+        }
+        Ok(())
+    }
+
+    /// Directions, in degrees, of the pellets of a shot aimed at `angle`,
+    /// spaced evenly over the spread so that every peer fires the same ones.
+    pub fn pellet_angles(&self, angle: f32) -> impl Iterator<Item = f32> + '_ {
+        let step = if self.pellets > 1 { self.spread_degrees / (self.pellets - 1) as f32 } else { 0.0 };
+        let first = angle - step * (self.pellets - 1) as f32 / 2.0;
+        (0..self.pellets).map(move |i| first + step * i as f32)
+    }
+}
+
+impl WeaponState {
+    /// A loaded copy of the weapon at `index`.
+    pub fn new(index: usize, weapon: &Weapon) -> Self {
+        WeaponState { weapon: index, ammo: weapon.magazine, cooldown: 0.0, reloading: 0.0 }
+    }
+
+    pub fn can_fire(&self) -> bool {
+        self.ammo > 0 && self.cooldown <= 0.0 && self.reloading <= 0.0
+    }
+
+    /// Uses up a round, starting the cooldown and, if that was the last one,
+    /// the reload.
+    pub fn fire(&mut self, weapon: &Weapon) {
+        self.ammo = self.ammo.saturating_sub(1);
+        self.cooldown = weapon.cooldown_secs;
+        if self.ammo == 0 {
+            self.reloading = weapon.reload_secs.max(f32::MIN_POSITIVE);
+        }
+    }
+
+    /// Runs the cooldown and reload timers for `delta_time` seconds.
+    pub fn update(&mut self, delta_time: f32, weapon: &Weapon) {
+        self.cooldown = (self.cooldown - delta_time).max(0.0);
+        if self.reloading > 0.0 {
+            self.reloading -= delta_time;
+            if self.reloading <= 0.0 {
+                self.reloading = 0.0;
+                self.ammo = weapon.magazine;
+            }
+        }
+    }
+}
+
+pub fn default_weapons() -> Vec<Weapon> {
+    vec![Weapon::pistol(), Weapon::shotgun(), Weapon::rifle()]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pellets_fan_out_over_the_spread() {
+        let angles: Vec<f32> = Weapon::shotgun().pellet_angles(90.0).collect();
+        assert_eq!(angles, [80.0, 84.0, 88.0, 92.0, 96.0, 100.0]);
+        assert_eq!(Weapon::pistol().pellet_angles(90.0).collect::<Vec<f32>>(), [90.0]);
+        assert!(Weapon { pellets: 0, ..Weapon::pistol() }.validate().is_err());
+        assert!(default_weapons().iter().all(|weapon| weapon.validate().is_ok()));
+    }
+
+    #[test]
+    fn magazines_empty_and_reload() {
+        let weapon = Weapon { magazine: 2, ..Weapon::pistol() };
+        let mut state = WeaponState::new(0, &weapon);
+        state.fire(&weapon);
+        assert!(!state.can_fire());
+        state.update(0.25, &weapon);
+        assert!(state.can_fire());
+        state.fire(&weapon);
+        assert_eq!(state.ammo, 0);
+        state.update(1.0, &weapon);
+        assert!(!state.can_fire());
+        state.update(0.5, &weapon);
+        assert!(state.can_fire());
+        assert_eq!(state.ammo, 2);
+    }
+}
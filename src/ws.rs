@@ -1,129 +1,324 @@
-use futures::{SinkExt, FutureExt, StreamExt};
+use futures::{SinkExt, StreamExt};
 use warp::ws::{Message, WebSocket};
-use crate::{Lobbies, Lobby, Player, game_state::{Vec2, self}};
+use std::sync::Arc;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::{Lobbies, SetupMessage, Channels, error::Error, game_state::{Vec2, GameEvent, PlayerInput, PLAYER_MAX_VEL}, snapshot::DeltaEncoder, codec::{self, Codec, CodecError}, protocol::{Hello, HandshakeError}};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
-use tokio::sync::{mpsc};
+use serde_json::from_str;
+use tokio::sync::{mpsc, oneshot, watch, broadcast::error::RecvError};
+use tokio::time::{self, Duration};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub enum Commands {
     UpdateVelocity {x: f32, y: f32},
     Shoot,
-    UpdateAngle(f32)
+    UpdateAngle(f32),
+    /// Takes out the weapon in the given inventory slot.
+    SwitchWeapon(usize)
 }
 
+/// A command as it travels over the socket, tagged with a sequence number
+/// that the server echoes back in `PlayerState::last_input_seq`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Input {
+    pub seq: u32,
+    pub command: Commands
+}
 
-pub async fn player_connection(ws: WebSocket, lobbies: Lobbies, lobby_name: String, player_name: String) {
-    let (mut ws_sender, mut ws_receiver) = ws.split();
-    //let (player_sender, player_rcv): (mpsc::UnboundedSender<GameEvent>, mpsc::UnboundedReceiver<GameEvent>) = mpsc::unbounded_channel();
-
-    lobbies.read().await.get(&lobby_name).unwrap().game_setup_sender
-        .send(crate::SetupMessage::AddPlayer(player_name.clone())).unwrap();
-    println!("sent add player message");
-    lobbies.read().await.get(&lobby_name).unwrap().game_setup_sender
-        .send(crate::SetupMessage::GetChannels).unwrap();
-    let mut channels = lobbies.write().await.get_mut(&lobby_name).expect("not found lobby name").game_ch_receiver.recv().await
-        .expect("could not receive channels");
+/// Everything a client may send over the socket.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Input(Input),
+    /// Acknowledges the snapshot taken at the given tick, so that the next
+    /// ones can be sent as deltas against it.
+    Ack(u64)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Malformed(String),
+    NotFinite
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Malformed(e) => write!(f, "malformed message: {}", e),
+            CommandError::NotFinite => write!(f, "command contains a non-finite number")
+        }
+    }
+}
+
+impl ClientMessage {
+    /// Parses a client message. Only `Commands` and acks are accepted over the
+    /// socket, so serialized `GameEvent`s like `Death` or `GameStateSync` are
+    /// rejected.
+    pub fn parse(msg: &str) -> Result<ClientMessage, CommandError> {
+        from_str(msg).map_err(|e| CommandError::Malformed(e.to_string()))
+    }
+}
+
+impl Input {
+    pub fn into_player_input(self, player_name: &str) -> Result<PlayerInput, CommandError> {
+        Ok(PlayerInput {
+            name: player_name.to_string(),
+            seq: self.seq,
+            event: self.command.into_event(player_name)?
+        })
+    }
+}
+
+impl Commands {
+    /// Turns the command into the event applied by the lobby loop, tagged with
+    /// the player name of the connection it arrived on.
+    pub fn into_event(self, player_name: &str) -> Result<GameEvent, CommandError> {
+        match self {
+            Commands::UpdateVelocity { x, y } => {
+                if !x.is_finite() || !y.is_finite() {
+                    return Err(CommandError::NotFinite);
+                }
+                let vel = Vec2 { x, y }.clamp_length(PLAYER_MAX_VEL);
+                Ok(GameEvent::UpdateVelocity { x: vel.x, y: vel.y, name: player_name.to_string() })
+            },
+            Commands::Shoot => Ok(GameEvent::Shooting(player_name.to_string())),
+            Commands::UpdateAngle(angle) => {
+                if !angle.is_finite() {
+                    return Err(CommandError::NotFinite);
+                }
+                Ok(GameEvent::UpdateAngle { angle: angle.rem_euclid(360.0), name: player_name.to_string() })
+            },
+            Commands::SwitchWeapon(slot) => Ok(GameEvent::SwitchWeapon { slot, name: player_name.to_string() })
+        }
+    }
+}
+
+/// Wraps an encoded event in a text or binary frame, depending on the codec.
+pub fn encode_event(codec: &dyn Codec, event: &GameEvent) -> Result<Message, CodecError> {
+    let bytes = codec.encode_event(event)?;
+    if codec.is_binary() {
+        Ok(Message::binary(bytes))
+    } else {
+        String::from_utf8(bytes).map(Message::text).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the client's `Hello` and answers with a `Welcome`, returning the
+/// codec picked for the rest of the connection. On failure the socket is
+/// closed with the matching `protocol` close code and reason.
+async fn handshake(ws: &mut WebSocket, preferred_codec: Option<&str>, player_name: &str, tick_rate: u32) -> Option<Arc<dyn Codec>> {
+    let hello = match time::timeout(HANDSHAKE_TIMEOUT, ws.next()).await {
+        Ok(Some(Ok(msg))) => msg.to_str()
+            .map_err(|_| HandshakeError::Malformed("expected a text Hello".to_string()))
+            .and_then(Hello::parse),
+        Ok(_) => return None,
+        Err(_) => Err(HandshakeError::Malformed("timed out waiting for Hello".to_string()))
+    };
+    match hello.and_then(|hello| hello.accept(preferred_codec, player_name, tick_rate)) {
+        Ok(welcome) => {
+            ws.send(Message::text(serde_json::to_string(&welcome).unwrap())).await.ok()?;
+            codec::by_name(&welcome.codec)
+        },
+        Err(e) => {
+            println!("handshake with {} failed: {}", player_name, e);
+            let _ = ws.send(Message::close_with(e.close_code(), e.to_string())).await;
+            None
+        }
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Announces the connection to the lobby loop and gets the lobby's channels
+/// back. Fails if the lobby was deleted or its loop stopped after the upgrade.
+async fn join_lobby(lobbies: &Lobbies, lobby_name: &str, player_name: &str, connection: u64)
+        -> std::result::Result<(mpsc::UnboundedSender<SetupMessage>, Channels), Error> {
+    let setup_sender = lobbies.read().await.get(lobby_name)
+        .map(|lobby| lobby.game_setup_sender.clone())
+        .ok_or_else(|| Error::LobbyNotFound(lobby_name.to_string()))?;
+    let closed = || Error::LobbyClosed(lobby_name.to_string());
+    setup_sender.send(SetupMessage::AddPlayer(player_name.to_string(), connection)).map_err(|_| closed())?;
+    let (channels_tx, channels_rx) = oneshot::channel();
+    setup_sender.send(SetupMessage::GetChannels(channels_tx)).map_err(|_| closed())?;
+    let channels = channels_rx.await.map_err(|_| closed())?;
+    Ok((setup_sender, channels))
+}
+
+pub async fn player_connection(mut ws: WebSocket, lobbies: Lobbies, lobby_name: String, player_name: String,
+        preferred_codec: Option<String>, tick_rate: u32) {
+    let codec = match handshake(&mut ws, preferred_codec.as_deref(), &player_name, tick_rate).await {
+        Some(codec) => codec,
+        None => return
+    };
+    let connection = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (setup_sender, channels) = match join_lobby(&lobbies, &lobby_name, &player_name, connection).await {
+        Ok(joined) => joined,
+        Err(e) => {
+            println!("{} could not join: {}", player_name, e);
+            if let Ok(msg) = encode_event(codec.as_ref(), &GameEvent::Error(e.to_string())) {
+                let _ = ws.send(msg).await;
+            }
+            let _ = ws.close().await;
+            return;
+        }
+    };
     println!("{:?} got the channels", player_name.clone());
-    // tokio::task::spawn(player_rcv.forward(ws_sender).map(|result| {
-    //     if let Err(e) = result {
-    //         eprintln!("error sending ws msg: {}", e);
-    //     }
-    // }));
-    tokio::task::spawn(async move {
-        let mut br_rx = channels.broadcast_receiver.take().unwrap();
+    let (mut ws_sender, mut ws_receiver) = ws.split();
+
+    // Forwards the lobby's broadcasts, and errors meant for this client only,
+    // to the socket until either side goes away: the lobby loop stopping, the
+    // socket refusing a send, or the receive loop below ending and dropping
+    // `ack_tx`.
+    let (ack_tx, mut ack_rx) = watch::channel(None);
+    let (error_tx, mut error_rx) = mpsc::unbounded_channel();
+    let sender_codec = codec.clone();
+    let mut br_rx = channels.broadcast_receiver;
+    let forward = tokio::task::spawn(async move {
+        let mut encoder = DeltaEncoder::new();
         loop {
-            let event = br_rx.recv().await.unwrap();
-            ws_sender.send(Message::text(to_string(&event).unwrap())).await.unwrap();
+            let event = tokio::select! {
+                event = br_rx.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("connection lagged behind by {} events", skipped);
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break
+                },
+                changed = ack_rx.changed() => match changed {
+                    Ok(()) => {
+                        if let Some(tick) = *ack_rx.borrow_and_update() {
+                            encoder.ack(tick);
+                        }
+                        continue;
+                    },
+                    Err(_) => break
+                },
+                Some(error) = error_rx.recv() => GameEvent::Error(error)
+            };
+            let event = match event {
+                GameEvent::GameStateSync(state) => encoder.encode(state),
+                event => event
+            };
+            let msg = match encode_event(sender_codec.as_ref(), &event) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    println!("could not encode {:?}: {}", event, e);
+                    continue;
+                }
+            };
+            if ws_sender.send(msg).await.is_err() {
+                break;
+            }
         }
+        let _ = ws_sender.close().await;
     });
 
     println!("listening");
     while let Some(result) = ws_receiver.next().await {
-        println!("received ws message");
         let msg = match  result {
             Ok(msg) => msg,
             Err(e) => {
                 println!("error receiving ws message for id: {}): {}", player_name.clone(), e);
-                //println!("error receiving ws message for id");
                 break;
             }
         };
-        channels.event_sender.as_ref().unwrap().send(from_str(&msg.to_str().unwrap()).unwrap()).unwrap();
-        //println!("received message from {}: {:?}", id, msg);
-        //player_msg(&id, msg, &lobbies, &lobby_name).await;
+        if msg.is_close() {
+            break;
+        }
+        if !msg.is_text() && !msg.is_binary() {
+            continue;
+        }
+        match codec.decode_message(msg.as_bytes()).map_err(|e| CommandError::Malformed(e.0)) {
+            Ok(ClientMessage::Ack(tick)) => {
+                ack_tx.send_replace(Some(tick));
+            },
+            Ok(ClientMessage::Input(input)) => match input.into_player_input(&player_name) {
+                Ok(input) => {
+                    if channels.event_sender.send(input).is_err() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    println!("rejected input from {}: {:?}", player_name, e);
+                    let _ = error_tx.send(e.to_string());
+                }
+            },
+            Err(e) => {
+                println!("rejected message from {}: {:?}", player_name, e);
+                let _ = error_tx.send(e.to_string());
+            }
+        }
     }
 
-    //lobbies.write().await.get_mut(&lobby_name).unwrap().players.remove(&id).unwrap();
+    println!("{:?} disconnected", player_name);
+    drop(ack_tx);
+    let _ = forward.await;
+    let _ = setup_sender.send(SetupMessage::Disconnected(player_name, connection));
+}
+
+#[cfg(test)]
+mod tests {
 
+    use super::*;
+    use serde_json::to_string;
 
-}
+    fn parse_input(msg: &str) -> Input {
+        match ClientMessage::parse(msg).unwrap() {
+            ClientMessage::Input(input) => input,
+            other => panic!("unexpected message {:?}", other)
+        }
+    }
 
-// async fn player_msg(id: &str, msg: Message, lobbies: &Lobbies, lobby_name: &str) {
-//     println!("received message from {}: {:?}", id, msg);
-//     let message = match msg.to_str() {
-//         Ok(v) => v,
-//         Err(_) => return,
-//     };
-
-//     if message == "ping" || message == "ping\n" {
-//         return;
-//     }
-
-//     let command: Commands = match  from_str(&message) {
-//         Ok(v) => v,
-//         Err(e)  => {
-//             eprintln!("error while parsing message to topics request: {}", e);
-//             return;
-//         }
-//     };
-
-//     match command {
-//         Commands::Shoot => {
-//             let mut locked = lobbies.write().await;
-//             let game = &mut locked.get_mut(lobby_name).unwrap().game;
-//             let player = game.players.get(id).unwrap();
-
-//             game.add_bullet(player.position.sum(&Vec2::with_angle(player.angle, 
-//                 game_state::PLAYER_RADIUS_SIZE + 1.0)), Vec2::with_angle(player.angle, game_state::BULLET_VEL));
-
-//             // locked.get(lobby_name).unwrap().players.iter().for_each(|(_, player)| {
-//             //     if let Some(sender) = &player.sender {
-//             //         let _ = sender.send(Ok(Message::text(to_string(&GameEvent::Shooting(id.to_string())).unwrap())));
-//             //     }
-//             // });
-//             broadcast_event(locked.get(lobby_name).unwrap(), GameEvent::Shooting(id.to_string()));
-
-//         },
-//         Commands::UpdateAngle(angle) => {
-//             let mut locked = lobbies.write().await;
-//             let game = &mut locked.get_mut(lobby_name).unwrap().game;
-//             let player = &mut game.players.get_mut(id).unwrap();
-
-//             player.angle = angle;
-
-//             broadcast_event(locked.get(lobby_name).unwrap(), GameEvent::UpdateAngle { angle: angle, name: id.to_string() });
-
-//         }, 
-//         Commands::UpdateVelocity { x, y } => {
-//             let mut locked = lobbies.write().await;
-//             let game = &mut locked.get_mut(lobby_name).unwrap().game;
-//             let player = &mut game.players.get_mut(id).unwrap();
-
-//             player.velocity.x = x;
-//             player.velocity.y = y;
-
-//             broadcast_event(locked.get(lobby_name).unwrap(), GameEvent::UpdateVelocity { x: x, y: y, name: id.to_string() });
-
-//         }
-//     }
-// }
-
-// pub fn broadcast_event(lobby: &Lobby, event: GameEvent) {
-//     println!("broadcasting: {:?}", event);
-//     lobby.players.iter().for_each(|(_, player)| {
-//         if let Some(sender) = &player.sender {
-//             let _ = sender.send(Ok(Message::text(to_string(&event).unwrap())));
-//         }
-//     });
-// }
+    #[test]
+    fn forged_events_are_rejected() {
+        let death = to_string(&GameEvent::Death { name: "someone".to_string(), killer: None }).unwrap();
+        assert!(matches!(ClientMessage::parse(&death), Err(CommandError::Malformed(_))));
+        let wrapped = format!(r#"{{"Input":{{"seq":1,"command":{}}}}}"#, death);
+        assert!(matches!(ClientMessage::parse(&wrapped), Err(CommandError::Malformed(_))));
+
+        let add = to_string(&GameEvent::AddPlayer { x: 0.0, y: 0.0, name: "other".to_string() }).unwrap();
+        assert!(matches!(ClientMessage::parse(&add), Err(CommandError::Malformed(_))));
+
+        let sync = r#"{"Input":{"seq":1,"command":{"GameStateSync":{"players":{},"bullets":[],"tick":0,"actions":[]}}}}"#;
+        assert!(matches!(ClientMessage::parse(sync), Err(CommandError::Malformed(_))));
+
+        assert!(matches!(ClientMessage::parse("not json"), Err(CommandError::Malformed(_))));
+    }
+
+    #[test]
+    fn commands_are_tagged_with_connection_name() {
+        let input = parse_input(r#"{"Input":{"seq":7,"command":"Shoot"}}"#).into_player_input("pl").unwrap();
+        assert_eq!(input.seq, 7);
+        assert_eq!(input.name, "pl");
+        assert!(matches!(input.event, GameEvent::Shooting(name) if name == "pl"));
+
+        let event = Commands::UpdateAngle(-90.0).into_event("pl").unwrap();
+        assert!(matches!(event, GameEvent::UpdateAngle { angle, name } if angle == 270.0 && name == "pl"));
+
+        let input = parse_input(r#"{"Input":{"seq":8,"command":{"SwitchWeapon":2}}}"#).into_player_input("pl").unwrap();
+        assert!(matches!(input.event, GameEvent::SwitchWeapon { slot: 2, name } if name == "pl"));
+    }
+
+    #[test]
+    fn velocity_is_clamped() {
+        let input = parse_input(r#"{"Input":{"seq":1,"command":{"UpdateVelocity":{"x":3000.0,"y":4000.0}}}}"#);
+        match input.into_player_input("pl").unwrap().event {
+            GameEvent::UpdateVelocity { x, y, .. } => {
+                assert!((x - PLAYER_MAX_VEL * 0.6).abs() < 1e-4);
+                assert!((y - PLAYER_MAX_VEL * 0.8).abs() < 1e-4);
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        let overflow = parse_input(r#"{"Input":{"seq":2,"command":{"UpdateVelocity":{"x":1e39,"y":0.0}}}}"#);
+        assert_eq!(overflow.into_player_input("pl").unwrap_err(), CommandError::NotFinite);
+    }
+
+    #[test]
+    fn acks_are_accepted() {
+        assert_eq!(ClientMessage::parse(r#"{"Ack":42}"#).unwrap(), ClientMessage::Ack(42));
+    }
+}
@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use futures::{SinkExt, StreamExt};
+use multiplayer_game::{codec, server_with_shutdown};
+use multiplayer_game::config::ServerConfig;
+use multiplayer_game::game_state::GameEvent;
+use multiplayer_game::handler::{CreateLobbyRequest, EnterLobby, LobbyResponse};
+use multiplayer_game::protocol::{Hello, Welcome};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::{self, protocol::Message};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub async fn start_server() -> SocketAddr {
+    start_server_with(ServerConfig::default()).await
+}
+
+pub async fn start_server_with(config: ServerConfig) -> SocketAddr {
+    let (addr, server) = server_with_shutdown(ServerConfig { bind: ([127, 0, 0, 1], 0).into(), ..config }, std::future::pending()).unwrap();
+    tokio::spawn(server);
+    addr
+}
+
+pub async fn start_server_with_reaper(idle_timeout: Duration) -> SocketAddr {
+    start_server_with(ServerConfig { idle_timeout, ..Default::default() }).await
+}
+
+/// Starts a server that shuts down with the reason sent through the returned
+/// sender; the handle finishes once it has stopped.
+pub async fn start_stoppable_server() -> (SocketAddr, oneshot::Sender<String>, JoinHandle<()>) {
+    let (stop_tx, stop_rx) = oneshot::channel::<String>();
+    let config = ServerConfig { bind: ([127, 0, 0, 1], 0).into(), ..Default::default() };
+    let (addr, server) = server_with_shutdown(config, async move { stop_rx.await.unwrap_or_default() }).unwrap();
+    (addr, stop_tx, tokio::spawn(server))
+}
+
+pub fn lobby_request(lobby: &str, player: &str, reconnect_grace_secs: f32) -> CreateLobbyRequest {
+    CreateLobbyRequest {
+        name: lobby.to_string(),
+        player_name: player.to_string(),
+        reconnect_grace_secs: Some(reconnect_grace_secs),
+        max_players: None,
+        password: None
+    }
+}
+
+pub async fn create_lobby_with(addr: SocketAddr, req: &CreateLobbyRequest) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/create_lobby", addr))
+        .json(req)
+        .send().await.unwrap()
+}
+
+pub async fn create_lobby(addr: SocketAddr, lobby: &str, player: &str, reconnect_grace_secs: f32) -> LobbyResponse {
+    create_lobby_with(addr, &lobby_request(lobby, player, reconnect_grace_secs)).await
+        .json().await.unwrap()
+}
+
+pub async fn register_with(addr: SocketAddr, lobby: &str, player: &str, password: Option<&str>) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/register", addr))
+        .json(&EnterLobby { name: lobby.to_string(), player_name: player.to_string(), password: password.map(str::to_string) })
+        .send().await.unwrap()
+}
+
+pub async fn register(addr: SocketAddr, lobby: &str, player: &str) -> reqwest::Response {
+    register_with(addr, lobby, player, None).await
+}
+
+pub async fn token_for(addr: SocketAddr, lobby: &str, player: &str) -> String {
+    let res: LobbyResponse = register(addr, lobby, player).await.json().await.unwrap();
+    res.token
+}
+
+pub async fn connect(addr: SocketAddr, lobby: &str, player: &str, token: &str) -> tungstenite::Result<Socket> {
+    let url = format!("ws://{}/ws/{}/{}?token={}", addr, lobby, player, token);
+    connect_async(url).await.map(|(socket, _)| socket)
+}
+
+pub async fn join(addr: SocketAddr, lobby: &str, player: &str, token: &str) -> Socket {
+    join_welcomed(addr, lobby, player, token).await.0
+}
+
+pub async fn join_welcomed(addr: SocketAddr, lobby: &str, player: &str, token: &str) -> (Socket, Welcome) {
+    let mut socket = connect(addr, lobby, player, token).await.unwrap();
+    let hello = serde_json::to_string(&Hello::new(&[codec::JSON])).unwrap();
+    socket.send(Message::Text(hello)).await.unwrap();
+    let welcome: Welcome = match socket.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+        other => panic!("expected a Welcome, got {:?}", other)
+    };
+    assert_eq!(welcome.player_id, player);
+    (socket, welcome)
+}
+
+/// Reads events until one matches `found`, or gives up after `wait`.
+pub async fn wait_for(socket: &mut Socket, wait: Duration, found: impl Fn(&GameEvent) -> bool) -> Option<GameEvent> {
+    timeout(wait, async {
+        while let Some(Ok(msg)) = socket.next().await {
+            if let Message::Text(text) = msg {
+                let event: GameEvent = serde_json::from_str(&text).unwrap();
+                if found(&event) {
+                    return Some(event);
+                }
+            }
+        }
+        None
+    }).await.ok().flatten()
+}
+
+pub fn has_player(event: &GameEvent, name: &str) -> bool {
+    matches!(event, GameEvent::GameStateSync(state) if state.players.contains_key(name))
+}
+
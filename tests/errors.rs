@@ -0,0 +1,118 @@
+mod common;
+
+use futures::SinkExt;
+use multiplayer_game::error::ErrorResponse;
+use multiplayer_game::game_state::GameEvent;
+use reqwest::StatusCode;
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::{self, protocol::Message};
+use common::*;
+
+async fn post(addr: std::net::SocketAddr, path: &str, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/{}", addr, path))
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .send().await.unwrap()
+}
+
+async fn error_of(res: reqwest::Response) -> (StatusCode, ErrorResponse) {
+    let status = res.status();
+    let body: ErrorResponse = res.json().await.unwrap();
+    assert_eq!(body.code, status.as_u16());
+    (status, body)
+}
+
+#[tokio::test]
+async fn unknown_lobby_is_not_found() {
+    let addr = start_server().await;
+    let (status, body) = error_of(register(addr, "nowhere", "pl").await).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body.message.contains("nowhere"));
+
+    let res = reqwest::Client::new().delete(format!("http://{}/create_lobby/nowhere", addr)).send().await.unwrap();
+    assert_eq!(error_of(res).await.0, StatusCode::NOT_FOUND);
+
+    match connect(addr, "nowhere", "pl", "token").await {
+        Err(tungstenite::Error::Http(res)) => assert_eq!(res.status(), 404),
+        other => panic!("expected a refused upgrade, got {:?}", other.map(|_| ()))
+    }
+}
+
+#[tokio::test]
+async fn bad_payloads_are_bad_requests() {
+    let addr = start_server().await;
+    assert_eq!(error_of(post(addr, "create_lobby", "{not json").await).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(error_of(post(addr, "register", r#"{"name": 5}"#).await).await.0, StatusCode::BAD_REQUEST);
+    for grace in [-1.0, 1e30] {
+        let res = create_lobby_with(addr, &lobby_request("grace", "alice", grace)).await;
+        assert_eq!(error_of(res).await.0, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn taken_name_is_a_conflict() {
+    let addr = start_server().await;
+    create_lobby(addr, "taken", "alice", 1.0).await;
+    let (status, body) = error_of(register(addr, "taken", "alice").await).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.message.contains("alice"));
+}
+
+#[tokio::test]
+async fn bad_messages_are_answered_with_errors() {
+    let addr = start_server().await;
+    let token = create_lobby(addr, "messages", "alice", 1.0).await.token;
+    let mut socket = join(addr, "messages", "alice", &token).await;
+
+    socket.send(Message::Text(r#"{"Death":"alice"}"#.to_string())).await.unwrap();
+    let error = wait_for(&mut socket, Duration::from_secs(2), |event| matches!(event, GameEvent::Error(_))).await;
+    assert!(matches!(error, Some(GameEvent::Error(message)) if message.contains("malformed")));
+
+    // The connection survives and the player is still in the game.
+    assert!(wait_for(&mut socket, Duration::from_secs(2), |event| has_player(event, "alice")).await.is_some());
+}
+
+#[tokio::test]
+async fn taken_lobby_name_is_a_conflict_and_keeps_the_game() {
+    let addr = start_server().await;
+    let token = create_lobby(addr, "original", "alice", 1.0).await.token;
+    let mut alice = join(addr, "original", "alice", &token).await;
+
+    let (status, body) = error_of(create_lobby_with(addr, &lobby_request("original", "mallory", 1.0)).await).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.message.contains("original"));
+    assert!(wait_for(&mut alice, Duration::from_secs(2), |event| has_player(event, "alice")).await.is_some());
+}
+
+#[tokio::test]
+async fn full_lobby_is_a_conflict() {
+    let addr = start_server().await;
+    let mut req = lobby_request("small", "alice", 1.0);
+    req.max_players = Some(2);
+    create_lobby_with(addr, &req).await;
+    assert_eq!(register(addr, "small", "bob").await.status(), StatusCode::OK);
+    let (status, body) = error_of(register(addr, "small", "carol").await).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.message.contains("full"));
+
+    req.name = "empty".to_string();
+    req.max_players = Some(0);
+    assert_eq!(error_of(create_lobby_with(addr, &req).await).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn password_is_checked() {
+    let addr = start_server().await;
+    let mut req = lobby_request("secret", "alice", 1.0);
+    req.password = Some("hunter2".to_string());
+    create_lobby_with(addr, &req).await;
+
+    assert_eq!(error_of(register(addr, "secret", "bob").await).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(error_of(register_with(addr, "secret", "bob", Some("hunter3")).await).await.0, StatusCode::FORBIDDEN);
+    let res = register_with(addr, "secret", "bob", Some("hunter2")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let lobbies: Vec<multiplayer_game::handler::LobbyInfo> = reqwest::get(format!("http://{}/lobbies", addr)).await.unwrap().json().await.unwrap();
+    assert!(lobbies[0].password_protected);
+}
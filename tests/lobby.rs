@@ -0,0 +1,249 @@
+mod common;
+
+use multiplayer_game::GameMode;
+use multiplayer_game::game_state::GameEvent;
+use multiplayer_game::config::{ServerConfig, DEFAULT_MAX_PLAYERS};
+use multiplayer_game::handler::{LobbyInfo, ScoreboardEntry};
+use multiplayer_game::codec;
+use multiplayer_game::protocol::{self, Hello};
+use futures::{SinkExt, StreamExt};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::{self, protocol::Message};
+use common::*;
+
+#[tokio::test]
+async fn dropped_player_leaves_after_grace_period() {
+    let addr = start_server().await;
+    let alice_token = create_lobby(addr, "drop", "alice", 0.3).await.token;
+    let mut alice = join(addr, "drop", "alice", &alice_token).await;
+    let bob_token = token_for(addr, "drop", "bob").await;
+    let mut bob = join(addr, "drop", "bob", &bob_token).await;
+    assert!(wait_for(&mut alice, Duration::from_secs(2), |event| has_player(event, "bob")).await.is_some());
+
+    bob.close(None).await.unwrap();
+    drop(bob);
+
+    let left = wait_for(&mut alice, Duration::from_secs(3), |event| matches!(event, GameEvent::PlayerLeft(_))).await;
+    assert!(matches!(left, Some(GameEvent::PlayerLeft(name)) if name == "bob"));
+    let sync = wait_for(&mut alice, Duration::from_secs(2), |event| matches!(event, GameEvent::GameStateSync(_))).await;
+    assert!(!has_player(&sync.unwrap(), "bob"));
+
+    // The session went away with the player.
+    assert!(connect(addr, "drop", "bob", &bob_token).await.is_err());
+}
+
+#[tokio::test]
+async fn reconnect_within_grace_period_keeps_player() {
+    let addr = start_server().await;
+    let alice_token = create_lobby(addr, "rejoin", "alice", 5.0).await.token;
+    let mut alice = join(addr, "rejoin", "alice", &alice_token).await;
+    let bob_token = token_for(addr, "rejoin", "bob").await;
+    let bob = join(addr, "rejoin", "bob", &bob_token).await;
+    drop(bob);
+
+    // A reconnect starts over with a full sync that still has the player.
+    let mut bob = join(addr, "rejoin", "bob", &bob_token).await;
+    let first = wait_for(&mut bob, Duration::from_secs(2), |event| {
+        matches!(event, GameEvent::GameStateSync(_) | GameEvent::GameStateDelta(_))
+    }).await.unwrap();
+    assert!(has_player(&first, "bob"));
+    let left = wait_for(&mut alice, Duration::from_millis(500), |event| matches!(event, GameEvent::PlayerLeft(_))).await;
+    assert!(left.is_none());
+}
+
+#[tokio::test]
+async fn version_mismatch_closes_with_reason() {
+    let addr = start_server().await;
+    let token = create_lobby(addr, "versions", "alice", 1.0).await.token;
+    let mut socket = connect(addr, "versions", "alice", &token).await.unwrap();
+    let mut hello = Hello::new(&[codec::JSON]);
+    hello.protocol_version = protocol::PROTOCOL_VERSION + 1;
+    socket.send(Message::Text(serde_json::to_string(&hello).unwrap())).await.unwrap();
+
+    match socket.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(u16::from(frame.code), protocol::CLOSE_VERSION_MISMATCH);
+            assert!(frame.reason.contains("version mismatch"));
+        },
+        other => panic!("expected a close frame, got {:?}", other)
+    }
+}
+
+#[tokio::test]
+async fn replaced_connection_does_not_drop_player() {
+    let addr = start_server().await;
+    let alice_token = create_lobby(addr, "replace", "alice", 0.3).await.token;
+    let mut alice = join(addr, "replace", "alice", &alice_token).await;
+    let bob_token = token_for(addr, "replace", "bob").await;
+    let old = join(addr, "replace", "bob", &bob_token).await;
+    let _new = join(addr, "replace", "bob", &bob_token).await;
+    drop(old);
+
+    let left = wait_for(&mut alice, Duration::from_secs(1), |event| matches!(event, GameEvent::PlayerLeft(_))).await;
+    assert!(left.is_none());
+}
+
+#[tokio::test]
+async fn sessions_are_required_and_unique() {
+    let addr = start_server().await;
+    let alice_token = create_lobby(addr, "sessions", "alice", 1.0).await.token;
+    let bob_token = token_for(addr, "sessions", "bob").await;
+    assert_ne!(alice_token, bob_token);
+
+    assert_eq!(register(addr, "sessions", "alice").await.status(), reqwest::StatusCode::CONFLICT);
+    for (player, token) in [("alice", bob_token.as_str()), ("alice", ""), ("carol", alice_token.as_str())] {
+        match connect(addr, "sessions", player, token).await {
+            Err(tungstenite::Error::Http(res)) => assert_eq!(res.status(), 401),
+            other => panic!("expected a refused upgrade, got {:?}", other.map(|_| ()))
+        }
+    }
+}
+
+async fn lobby_info(addr: std::net::SocketAddr, path: &str) -> reqwest::Response {
+    reqwest::get(format!("http://{}/{}", addr, path)).await.unwrap()
+}
+
+#[tokio::test]
+async fn lobbies_are_listed_with_their_players() {
+    let addr = start_server().await;
+    create_lobby(addr, "second", "carol", 1.0).await;
+    let alice_token = create_lobby(addr, "first", "alice", 1.0).await.token;
+
+    let lobbies: Vec<LobbyInfo> = lobby_info(addr, "lobbies").await.json().await.unwrap();
+    assert_eq!(lobbies.iter().map(|lobby| lobby.name.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+    assert_eq!(lobbies[0].players, ["alice"]);
+    assert_eq!(lobbies[0].max_players, DEFAULT_MAX_PLAYERS);
+    assert_eq!(lobbies[0].game_mode, GameMode::FreeForAll);
+
+    let _alice = join(addr, "first", "alice", &alice_token).await;
+    let bob_token = token_for(addr, "first", "bob").await;
+    let _bob = join(addr, "first", "bob", &bob_token).await;
+    let info = timeout(Duration::from_secs(2), async {
+        loop {
+            let info: LobbyInfo = lobby_info(addr, "lobbies/first").await.json().await.unwrap();
+            if info.player_count == 2 {
+                return info;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }).await.unwrap();
+    assert_eq!(info.players, ["alice", "bob"]);
+    assert!(info.uptime_secs > 0.0);
+
+    assert_eq!(lobby_info(addr, "lobbies/nowhere").await.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn scoreboard_lists_everyone_who_joined() {
+    let addr = start_server().await;
+    let alice_token = create_lobby(addr, "scores", "alice", 1.0).await.token;
+    let _alice = join(addr, "scores", "alice", &alice_token).await;
+    let bob_token = token_for(addr, "scores", "bob").await;
+    let _bob = join(addr, "scores", "bob", &bob_token).await;
+
+    let scoreboard = timeout(Duration::from_secs(2), async {
+        loop {
+            let scoreboard: Vec<ScoreboardEntry> = lobby_info(addr, "lobbies/scores/scoreboard").await.json().await.unwrap();
+            if scoreboard.len() == 2 {
+                return scoreboard;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }).await.unwrap();
+    assert_eq!(scoreboard, [
+        ScoreboardEntry { name: "alice".to_string(), kills: 0, deaths: 0, damage_dealt: 0, accuracy: 0.0 },
+        ScoreboardEntry { name: "bob".to_string(), kills: 0, deaths: 0, damage_dealt: 0, accuracy: 0.0 }
+    ]);
+
+    assert_eq!(lobby_info(addr, "lobbies/nowhere/scoreboard").await.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_lobby_notifies_players_and_closes_sockets() {
+    let addr = start_server().await;
+    let token = create_lobby(addr, "doomed", "alice", 1.0).await.token;
+    let mut alice = join(addr, "doomed", "alice", &token).await;
+    // Only once the sync arrives is the connection subscribed to the lobby.
+    assert!(wait_for(&mut alice, Duration::from_secs(2), |event| has_player(event, "alice")).await.is_some());
+
+    let res = reqwest::Client::new().delete(format!("http://{}/create_lobby/doomed", addr)).send().await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+    assert!(wait_for(&mut alice, Duration::from_secs(2), |event| matches!(event, GameEvent::LobbyClosed)).await.is_some());
+    let closed = timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = alice.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    }).await;
+    assert!(closed.is_ok());
+    assert_eq!(lobby_info(addr, "lobbies/doomed").await.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn idle_lobbies_are_reaped() {
+    let addr = start_server_with_reaper(Duration::from_millis(200)).await;
+    create_lobby(addr, "idle", "alice", 0.1).await;
+    let token = create_lobby(addr, "busy", "bob", 0.1).await.token;
+    let _bob = join(addr, "busy", "bob", &token).await;
+
+    let reaped = timeout(Duration::from_secs(3), async {
+        while lobby_info(addr, "lobbies/idle").await.status() != reqwest::StatusCode::NOT_FOUND {
+            sleep(Duration::from_millis(50)).await;
+        }
+    }).await;
+    assert!(reaped.is_ok());
+    assert_eq!(lobby_info(addr, "lobbies/busy").await.status(), reqwest::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn registering_keeps_idle_lobbies_open_until_the_player_connects() {
+    let addr = start_server_with_reaper(Duration::from_secs(1)).await;
+    create_lobby(addr, "waiting", "alice", 0.05).await;
+    // Alice never connects, so the lobby is empty for most of the timeout
+    // before bob registers.
+    sleep(Duration::from_millis(800)).await;
+    let token = token_for(addr, "waiting", "bob").await;
+
+    sleep(Duration::from_millis(700)).await;
+    assert_eq!(lobby_info(addr, "lobbies/waiting").await.status(), reqwest::StatusCode::OK);
+    let _bob = join(addr, "waiting", "bob", &token).await;
+}
+
+#[tokio::test]
+async fn config_sets_public_url_and_tick_rate() {
+    let mut config = ServerConfig {
+        public_url: Some("wss://game.example.com/".to_string()),
+        tick_rate: 60,
+        ..Default::default()
+    };
+    config.lobby.max_players = 3;
+    let addr = start_server_with(config).await;
+
+    let res = create_lobby(addr, "configured", "alice", 1.0).await;
+    assert_eq!(res.url, "wss://game.example.com/ws/configured/alice");
+    let info: LobbyInfo = lobby_info(addr, "lobbies/configured").await.json().await.unwrap();
+    assert_eq!(info.max_players, 3);
+
+    let (_alice, welcome) = join_welcomed(addr, "configured", "alice", &res.token).await;
+    assert_eq!(welcome.tick_rate, 60);
+}
+
+#[tokio::test]
+async fn shutdown_notifies_players_and_stops_the_server() {
+    let (addr, stop, server) = start_stoppable_server().await;
+    let token = create_lobby(addr, "lobby", "pl", 5.0).await.token;
+    let mut socket = join(addr, "lobby", "pl", &token).await;
+    assert!(wait_for(&mut socket, Duration::from_secs(2), |event| has_player(event, "pl")).await.is_some());
+
+    stop.send("maintenance".to_string()).unwrap();
+    let event = wait_for(&mut socket, Duration::from_secs(2), |event| matches!(event, GameEvent::ServerShutdown { .. })).await;
+    assert!(matches!(event, Some(GameEvent::ServerShutdown { reason }) if reason == "maintenance"));
+    let closed = timeout(Duration::from_secs(2), async {
+        while let Some(Ok(_)) = socket.next().await {}
+    }).await;
+    assert!(closed.is_ok(), "socket was not closed");
+
+    timeout(Duration::from_secs(5), server).await.expect("server did not stop").unwrap();
+    assert!(reqwest::get(format!("http://{}/lobbies", addr)).await.is_err());
+}